@@ -0,0 +1,18 @@
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tcp_over_quic::quic_tunnel::tlv::{Decoder, Tlv};
+
+fuzz_target!(|data: &[u8]| {
+    // every TLV accepted by the decoder must encode back to the same bytes
+    if let Ok((tlv, n)) = Tlv::decode(data) {
+        let mut buf = BytesMut::new();
+        tlv.encode(&mut buf);
+        assert_eq!(&data[..n], &buf[..]);
+    }
+
+    // the streaming decoder must never panic
+    let mut decoder = Decoder::new();
+    decoder.extend(data);
+    while let Ok(Some(_)) = decoder.next_tlv() {}
+});
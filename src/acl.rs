@@ -0,0 +1,296 @@
+use crate::quic_tunnel::destination::{parse_port_range, Destination};
+use crate::quic_tunnel::tlv::{self, TlvError};
+use anyhow::{anyhow, ensure, Result};
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+use tracing::debug;
+
+/// Network and ports matched by an ACL rule.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    net: IpAddr,
+    prefix: u8,
+    ports: (u16, u16),
+}
+
+impl Rule {
+    pub fn matches(&self, addr: &SocketAddr) -> bool {
+        let ip = canonical(addr.ip());
+        let in_net = match (self.net, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                prefix_matches(&net.octets(), &ip.octets(), self.prefix)
+            }
+            _ => false,
+        };
+        in_net && (self.ports.0..=self.ports.1).contains(&addr.port())
+    }
+}
+
+impl FromStr for Rule {
+    type Err = anyhow::Error;
+
+    /// Parses `ip[/prefix][:ports]` where ports is a port or a range `start-end`,
+    /// IPv6 networks are written in brackets when followed by ports
+    /// e.g. `10.0.0.0/8`, `10.0.0.1:5432` or `[fd00::/8]:8000-8099`.
+    fn from_str(s: &str) -> Result<Rule> {
+        let (net, ports) = if let Some(rest) = s.strip_prefix('[') {
+            let (net, ports) = rest
+                .split_once(']')
+                .ok_or_else(|| anyhow!("missing ] in acl rule {}", s))?;
+            let ports = match ports {
+                "" => None,
+                p => Some(
+                    p.strip_prefix(':')
+                        .ok_or_else(|| anyhow!("invalid acl rule {}", s))?,
+                ),
+            };
+            (net, ports)
+        } else if s.matches(':').count() == 1 {
+            let (net, ports) = s.split_once(':').unwrap();
+            (net, Some(ports))
+        } else {
+            (s, None)
+        };
+
+        let (ip, prefix) = net.split_once('/').unwrap_or((net, ""));
+        let net: IpAddr = ip
+            .parse()
+            .map_err(|_| anyhow!("invalid address in acl rule {}", s))?;
+        let max_prefix = if net.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            "" => max_prefix,
+            p => p
+                .parse()
+                .map_err(|_| anyhow!("invalid prefix in acl rule {}", s))?,
+        };
+        ensure!(prefix <= max_prefix, "invalid prefix in acl rule {}", s);
+
+        let ports = match ports {
+            Some(p) => parse_port_range(p)?,
+            None => (0, u16::MAX),
+        };
+
+        Ok(Rule {
+            net: canonical(net),
+            prefix,
+            ports,
+        })
+    }
+}
+
+/// Destinations the concentrator may connect to on behalf of clients.
+///
+/// Denied destinations are never reached, allowed ones are reached even if
+/// denied by default. By default loopback, link-local, multicast and the
+/// concentrator's own addresses are denied, and when any destination is
+/// allowed every other one is denied.
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    pub allow: Vec<Rule>,
+    pub deny: Vec<Rule>,
+    /// Addresses of the concentrator's interfaces
+    pub local_addrs: Vec<IpAddr>,
+    /// Destinations allowed to the clients authenticated with a certificate
+    /// of these identities, in place of `allow`
+    pub identity_allow: HashMap<String, Vec<Rule>>,
+}
+
+impl Acl {
+    /// Returns the ACL of the connections of a client authenticated as `identity`.
+    pub fn for_identity(self: &Arc<Self>, identity: Option<&str>) -> Arc<Acl> {
+        match identity.and_then(|id| self.identity_allow.get(id)) {
+            Some(allow) => self.with_allow(allow.clone()),
+            None => self.clone(),
+        }
+    }
+
+    /// Returns this ACL with `allow` in place of its allowed destinations.
+    pub fn with_allow(&self, allow: Vec<Rule>) -> Arc<Acl> {
+        Arc::new(Acl {
+            allow,
+            deny: self.deny.clone(),
+            local_addrs: self.local_addrs.clone(),
+            identity_allow: HashMap::new(),
+        })
+    }
+
+    pub fn is_allowed(&self, addr: &SocketAddr) -> bool {
+        if self.deny.iter().any(|r| r.matches(addr)) {
+            return false;
+        }
+        if self.allow.iter().any(|r| r.matches(addr)) {
+            return true;
+        }
+        let ip = canonical(addr.ip());
+        if is_denied_by_default(ip) || self.local_addrs.iter().any(|a| canonical(*a) == ip) {
+            return false;
+        }
+        self.allow.is_empty()
+    }
+
+    /// Keep the addresses `dest` resolved to which may be connected to.
+    /// Returns the error to send back to the client if none is left.
+    pub fn filter(
+        &self,
+        dest: &Destination,
+        addrs: Vec<SocketAddr>,
+    ) -> std::result::Result<Vec<SocketAddr>, TlvError> {
+        let (allowed, denied): (Vec<_>, Vec<_>) =
+            addrs.into_iter().partition(|a| self.is_allowed(a));
+        if !denied.is_empty() {
+            debug!("{} denied by acl {:?}", dest, denied);
+        }
+        if allowed.is_empty() {
+            return Err(TlvError::new(
+                tlv::ERROR_TYPE_ACCESS_DENIED,
+                format!("{} is not allowed", dest),
+            ));
+        }
+        Ok(allowed)
+    }
+}
+
+/// Undo the IPv4-mapped encoding, so rules on IPv4 networks apply.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        ip => ip,
+    }
+}
+
+fn is_denied_by_default(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(v4) => {
+            v4.is_loopback()
+                || v4.is_link_local()
+                || v4.is_unspecified()
+                || v4.is_multicast()
+                || v4.is_broadcast()
+        }
+        IpAddr::V6(v6) => {
+            // fe80::/10
+            v6.is_loopback()
+                || (v6.segments()[0] & 0xffc0) == 0xfe80
+                || v6.is_unspecified()
+                || v6.is_multicast()
+        }
+    }
+}
+
+fn prefix_matches(net: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let (bytes, bits) = ((prefix / 8) as usize, prefix % 8);
+    if net[..bytes] != ip[..bytes] {
+        return false;
+    }
+    bits == 0 || (net[bytes] ^ ip[bytes]) >> (8 - bits) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr(s: &str) -> SocketAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parse_rule_test() {
+        let rule: Rule = "10.0.0.0/8:5432".parse().unwrap();
+        assert!(rule.matches(&addr("10.1.2.3:5432")));
+        assert!(!rule.matches(&addr("10.1.2.3:5433")));
+        assert!(!rule.matches(&addr("11.0.0.1:5432")));
+        assert!(rule.matches(&addr("[::ffff:10.0.0.1]:5432")));
+
+        let rule: Rule = "[fd00::/8]:8000-8099".parse().unwrap();
+        assert!(rule.matches(&addr("[fd12::1]:8080")));
+        assert!(!rule.matches(&addr("[fe80::1]:8080")));
+
+        let rule: Rule = "fd00::1".parse().unwrap();
+        assert!(rule.matches(&addr("[fd00::1]:22")));
+        assert!(!rule.matches(&addr("[fd00::2]:22")));
+
+        let rule: Rule = "192.168.1.0/23".parse().unwrap();
+        assert!(rule.matches(&addr("192.168.0.7:80")));
+        assert!(!rule.matches(&addr("192.168.2.7:80")));
+
+        for invalid in &["10.0.0.0/33", "db.internal", "10.0.0.1:80-70", "[fd00::1"] {
+            assert!(invalid.parse::<Rule>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn default_acl_test() {
+        let acl = Acl {
+            local_addrs: vec!["192.0.2.10".parse().unwrap()],
+            ..Acl::default()
+        };
+        assert!(acl.is_allowed(&addr("10.0.0.1:5432")));
+        for denied in &[
+            "127.0.0.1:22",
+            "[::1]:22",
+            "[::ffff:127.0.0.1]:22",
+            "169.254.169.254:80",
+            "[fe80::1]:80",
+            "0.0.0.0:22",
+            "224.0.0.1:80",
+            "192.0.2.10:22",
+        ] {
+            assert!(!acl.is_allowed(&addr(denied)), "{}", denied);
+        }
+    }
+
+    #[test]
+    fn allow_deny_test() {
+        let acl = Acl {
+            allow: vec![
+                "10.0.0.0/8".parse().unwrap(),
+                "127.0.0.1:7970".parse().unwrap(),
+            ],
+            deny: vec!["10.0.0.1".parse().unwrap()],
+            ..Acl::default()
+        };
+        assert!(acl.is_allowed(&addr("10.0.0.2:22")));
+        assert!(acl.is_allowed(&addr("127.0.0.1:7970")));
+        assert!(!acl.is_allowed(&addr("127.0.0.1:22")));
+        assert!(!acl.is_allowed(&addr("10.0.0.1:22")));
+        assert!(!acl.is_allowed(&addr("192.168.0.1:22")));
+
+        let dest = Destination::Host("db.internal".to_string(), 22);
+        let err = acl.filter(&dest, vec![addr("10.0.0.1:22")]).unwrap_err();
+        assert_eq!(tlv::ERROR_TYPE_ACCESS_DENIED, err.code);
+        assert_eq!(
+            vec![addr("10.0.0.2:22")],
+            acl.filter(&dest, vec![addr("10.0.0.1:22"), addr("10.0.0.2:22")])
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn identity_acl_test() {
+        let mut identity_allow = HashMap::new();
+        identity_allow.insert("alice".to_string(), vec!["10.0.0.0/8".parse().unwrap()]);
+        let acl = Arc::new(Acl {
+            allow: vec!["10.1.0.0/16".parse().unwrap()],
+            deny: vec!["10.0.0.1".parse().unwrap()],
+            identity_allow,
+            ..Acl::default()
+        });
+
+        let alice = acl.for_identity(Some("alice"));
+        assert!(alice.is_allowed(&addr("10.2.0.1:22")));
+        assert!(!alice.is_allowed(&addr("10.0.0.1:22")));
+
+        for other in &[acl.for_identity(Some("bob")), acl.for_identity(None)] {
+            assert!(other.is_allowed(&addr("10.1.0.1:22")));
+            assert!(!other.is_allowed(&addr("10.2.0.1:22")));
+        }
+    }
+}
@@ -0,0 +1,366 @@
+use crate::acl::Rule;
+use crate::quic_tunnel::connection::{read_tlv, write_tlv};
+use crate::quic_tunnel::tlv::{self, Tlv, TlvError, Token};
+use crate::quic_tunnel::version::Version;
+use anyhow::{anyhow, ensure, Result};
+use bytes::BytesMut;
+use futures::StreamExt;
+use quinn::VarInt;
+use ring::{constant_time, digest};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::time;
+use tracing::error;
+
+/// Application close code of connections which didn't authenticate,
+/// or whose token expired.
+pub const CLOSE_UNAUTHENTICATED: VarInt = VarInt::from_u32(1);
+
+/// Token of the token file.
+#[derive(Debug, Clone)]
+pub struct Entry {
+    /// Identity of the clients authenticated with the token
+    pub name: String,
+    /// SHA-256 of the token
+    hash: Vec<u8>,
+    /// `None` if the token never expires
+    pub expires: Option<SystemTime>,
+    /// Destinations the clients may connect to, the concentrator's
+    /// ACL applies if empty
+    pub allow: Vec<Rule>,
+}
+
+/// Tokens clients may authenticate with, one per line:
+///
+/// `name sha256 expires [rule,...]`
+///
+/// where `sha256` is the hex encoded hash of the token, `expires` a unix
+/// timestamp or `never`, and the rules the destinations the clients may
+/// connect to. Empty lines and lines starting with `#` are ignored.
+#[derive(Debug, Clone)]
+pub struct TokenFile {
+    path: PathBuf,
+}
+
+impl TokenFile {
+    /// Check the file at `path` is a valid token file.
+    pub fn open(path: impl AsRef<Path>) -> Result<TokenFile> {
+        let path = path.as_ref().to_path_buf();
+        let data = fs::read_to_string(&path)
+            .map_err(|e| anyhow!("unable to read {} {}", path.display(), e))?;
+        parse_tokens(&data)?;
+        Ok(TokenFile { path })
+    }
+
+    /// Returns the entry of `token`.
+    /// The file is read again so tokens can be added or revoked while running.
+    pub async fn verify(&self, token: &Token) -> std::result::Result<Entry, TlvError> {
+        let entries = tokio::fs::read_to_string(&self.path)
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(|data| parse_tokens(&data))
+            .map_err(|e| {
+                error!("unable to read {} {}", self.path.display(), e);
+                TlvError::new(tlv::ERROR_TYPE_ACCESS_DENIED, "unable to verify token")
+            })?;
+        find(entries, token, SystemTime::now())
+    }
+
+    /// Authenticate the client of a new connection, its first stream must
+    /// carry an Auth TLV followed by the End TLV.
+    /// Returns the entry of the client's token.
+    pub async fn accept(
+        &self,
+        bi_streams: &mut quinn::IncomingBiStreams,
+        timeout: Duration,
+    ) -> Result<Entry> {
+        let (mut quic_send, mut quic_recv) = time::timeout(timeout, bi_streams.next())
+            .await
+            .map_err(|_| anyhow!("timed out waiting for the Auth TLV"))?
+            .ok_or_else(|| anyhow!("quic connection closed"))??;
+
+        let mut decoder = tlv::Decoder::new();
+        let request = time::timeout(timeout, async {
+            let auth = read_tlv(&mut quic_recv, &mut decoder).await?;
+            let end = read_tlv(&mut quic_recv, &mut decoder).await?;
+            Ok::<_, anyhow::Error>((auth, end))
+        })
+        .await
+        .map_err(|_| anyhow!("timed out waiting for the Auth TLV"))??;
+        let token = match request {
+            (Some(Tlv::Auth(token)), Some(Tlv::End)) => token,
+            _ => {
+                let e = TlvError::protocol_violation("expected Auth TLV on the first stream");
+                write_tlv(&mut quic_send, &Tlv::Error(e.clone())).await?;
+                return Err(e.into());
+            }
+        };
+
+        match self.verify(&token).await {
+            Ok(entry) => {
+                write_tlv(&mut quic_send, &Tlv::TcpConnectOk).await?;
+                quic_send.finish().await?;
+                Ok(entry)
+            }
+            Err(e) => {
+                write_tlv(&mut quic_send, &Tlv::Error(e.clone())).await?;
+                Err(e.into())
+            }
+        }
+    }
+}
+
+/// Token the client authenticates with on each new connection.
+#[derive(Clone)]
+pub struct Credentials {
+    pub token: Token,
+
+    /// Time allowed for the concentrator's reply
+    pub handshake_timeout: Duration,
+}
+
+impl Credentials {
+    /// Read the token of the file at `path`, surrounding whitespace is ignored.
+    pub fn load(path: impl AsRef<Path>, handshake_timeout: Duration) -> Result<Credentials> {
+        let path = path.as_ref();
+        let data = fs::read_to_string(path)
+            .map_err(|e| anyhow!("unable to read {} {}", path.display(), e))?;
+        let token = data.trim().as_bytes();
+        // check the token fits in an Auth TLV
+        Tlv::auth(token)?;
+        Ok(Credentials {
+            token: Token(token.to_vec()),
+            handshake_timeout,
+        })
+    }
+
+    /// Authenticate on the first stream of a new connection.
+    pub async fn authenticate(
+        &self,
+        connection: &quinn::Connection,
+        version: Version,
+    ) -> Result<()> {
+        ensure!(
+            version.supports_auth(),
+            "{} doesn't support authentication",
+            version
+        );
+
+        let (mut quic_send, mut quic_recv) =
+            time::timeout(self.handshake_timeout, connection.open_bi())
+                .await
+                .map_err(|_| anyhow!("timed out opening quic stream"))??;
+
+        let mut buf = BytesMut::new();
+        Tlv::Auth(self.token.clone()).encode(&mut buf);
+        Tlv::End.encode(&mut buf);
+        quic_send.write_all(&buf).await?;
+        quic_send.finish().await?;
+
+        let mut decoder = tlv::Decoder::new();
+        let reply = time::timeout(
+            self.handshake_timeout,
+            read_tlv(&mut quic_recv, &mut decoder),
+        )
+        .await
+        .map_err(|_| anyhow!("timed out waiting for the concentrator's reply"))??;
+        match reply {
+            Some(Tlv::TcpConnectOk) => Ok(()),
+            Some(Tlv::Error(e)) => Err(e.into()),
+            Some(t) => Err(TlvError::protocol_violation(format!(
+                "expected TCP Connect OK TLV received {:?}",
+                t
+            ))
+            .into()),
+            None => Err(anyhow!("quic stream finished")),
+        }
+    }
+}
+
+/// `true` if `authenticate` failed because the concentrator refused the token
+/// or doesn't take tokens, which reconnecting with the same token can't fix.
+pub fn is_rejected(e: &anyhow::Error) -> bool {
+    let closed = match (e.downcast_ref(), e.downcast_ref(), e.downcast_ref()) {
+        (Some(e), _, _) => Some(e),
+        (_, Some(quinn::ReadError::ConnectionClosed(e)), _) => Some(e),
+        (_, _, Some(quinn::WriteError::ConnectionClosed(e))) => Some(e),
+        _ => None,
+    };
+    if let Some(quinn::ConnectionError::ApplicationClosed(close)) = closed {
+        return close.error_code == CLOSE_UNAUTHENTICATED;
+    }
+    e.is::<TlvError>()
+        || matches!(e.downcast_ref(), Some(quinn::ReadError::Reset(_)))
+        || matches!(e.downcast_ref(), Some(quinn::WriteError::Stopped(_)))
+}
+
+fn parse_tokens(data: &str) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+    for (n, line) in data.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let entry = parse_entry(line).map_err(|e| anyhow!("line {}: {}", n + 1, e))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+fn parse_entry(line: &str) -> Result<Entry> {
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    ensure!(
+        fields.len() == 3 || fields.len() == 4,
+        "expected name sha256 expires [rules]"
+    );
+
+    let hash = decode_hex(fields[1]).ok_or_else(|| anyhow!("invalid sha256 {}", fields[1]))?;
+    ensure!(
+        hash.len() == digest::SHA256_OUTPUT_LEN,
+        "invalid sha256 {}",
+        fields[1]
+    );
+    let expires = match fields[2] {
+        "never" => None,
+        t => Some(
+            UNIX_EPOCH
+                + Duration::from_secs(t.parse().map_err(|_| anyhow!("invalid expiry {}", t))?),
+        ),
+    };
+    let allow = match fields.get(3) {
+        Some(rules) => rules
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<Rule>>>()?,
+        None => Vec::new(),
+    };
+
+    Ok(Entry {
+        name: fields[0].to_string(),
+        hash,
+        expires,
+        allow,
+    })
+}
+
+fn find(
+    entries: Vec<Entry>,
+    token: &Token,
+    now: SystemTime,
+) -> std::result::Result<Entry, TlvError> {
+    let hash = digest::digest(&digest::SHA256, &token.0);
+    let entry = entries
+        .into_iter()
+        .find(|e| constant_time::verify_slices_are_equal(&e.hash, hash.as_ref()).is_ok())
+        .ok_or_else(|| TlvError::new(tlv::ERROR_TYPE_ACCESS_DENIED, "unknown token"))?;
+    if entry.expires.is_some_and(|expires| expires <= now) {
+        return Err(TlvError::new(
+            tlv::ERROR_TYPE_ACCESS_DENIED,
+            "token expired",
+        ));
+    }
+    Ok(entry)
+}
+
+fn decode_hex(s: &str) -> Option<Vec<u8>> {
+    s.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [_, _] => u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok(),
+            _ => None,
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKENS: &str = "
+# name sha256 expires allow
+ci 8d7fea2f7e1d5e2a7b6fd0c1b4be2f3f9e2ab5a89fa5bd6b3b4e2b0c4ebd4d0a never
+contractor 2c4aa1b4b4b3e6b62ba1c1cf0c9b4b6b1e6e5c43ecbc3db55fb71b8f0fab2c60 1700000000 10.1.0.0/16,10.2.0.5:22
+";
+
+    fn hex(data: &[u8]) -> String {
+        digest::digest(&digest::SHA256, data)
+            .as_ref()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect()
+    }
+
+    #[test]
+    fn is_rejected_test() {
+        let e = TlvError::new(tlv::ERROR_TYPE_ACCESS_DENIED, "unknown token");
+        assert!(is_rejected(&e.into()));
+        assert!(is_rejected(
+            &quinn::ReadError::Reset(VarInt::from_u32(0)).into()
+        ));
+
+        let close = |error_code| {
+            quinn::ConnectionError::ApplicationClosed(quinn::ApplicationClose {
+                error_code,
+                reason: Default::default(),
+            })
+        };
+        assert!(is_rejected(&close(CLOSE_UNAUTHENTICATED).into()));
+        assert!(is_rejected(
+            &quinn::ReadError::ConnectionClosed(close(CLOSE_UNAUTHENTICATED)).into()
+        ));
+        assert!(!is_rejected(&close(VarInt::from_u32(0)).into()));
+        assert!(!is_rejected(
+            &quinn::WriteError::ConnectionClosed(quinn::ConnectionError::TimedOut).into()
+        ));
+        assert!(!is_rejected(&anyhow!("timed out opening quic stream")));
+    }
+
+    #[test]
+    fn parse_tokens_test() {
+        let entries = parse_tokens(TOKENS).unwrap();
+        assert_eq!(2, entries.len());
+        assert_eq!("ci", entries[0].name);
+        assert_eq!(None, entries[0].expires);
+        assert!(entries[0].allow.is_empty());
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+            entries[1].expires
+        );
+        assert_eq!(2, entries[1].allow.len());
+
+        for invalid in &[
+            "ci",
+            "ci 8d7f never",
+            "ci zz never",
+            "ci 8d7fea2f7e1d5e2a7b6fd0c1b4be2f3f9e2ab5a89fa5bd6b3b4e2b0c4ebd4d0a soon",
+            "ci 8d7fea2f7e1d5e2a7b6fd0c1b4be2f3f9e2ab5a89fa5bd6b3b4e2b0c4ebd4d0a never db.internal",
+        ] {
+            assert!(parse_tokens(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn find_test() {
+        let tokens = format!(
+            "ci {} never\ncontractor {} 1700000000 10.1.0.0/16",
+            hex(b"s3cret"),
+            hex(b"expired")
+        );
+        let before = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let after = UNIX_EPOCH + Duration::from_secs(1_800_000_000);
+        let token = |t: &[u8]| Token(t.to_vec());
+
+        let entry = find(parse_tokens(&tokens).unwrap(), &token(b"s3cret"), after).unwrap();
+        assert_eq!("ci", entry.name);
+
+        let entry = find(parse_tokens(&tokens).unwrap(), &token(b"expired"), before).unwrap();
+        assert_eq!("contractor", entry.name);
+        let err = find(parse_tokens(&tokens).unwrap(), &token(b"expired"), after).unwrap_err();
+        assert_eq!(tlv::ERROR_TYPE_ACCESS_DENIED, err.code);
+        assert_eq!("token expired", err.reason);
+
+        let err = find(parse_tokens(&tokens).unwrap(), &token(b"guess"), before).unwrap_err();
+        assert_eq!("unknown token", err.reason);
+    }
+}
@@ -0,0 +1,315 @@
+use crate::pool::Pool;
+use crate::supervisor::Tunnel;
+use anyhow::{anyhow, bail, Result};
+use futures::future::{self, FutureExt};
+use std::fmt;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::time::{self, Duration, Instant};
+
+/// Concentrator the client can tunnel to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Concentrator {
+    pub addr: SocketAddr,
+    /// Server name used in the concentrator's certificate, defaults to `--quic_serv_name`
+    pub name: Option<String>,
+    /// Concentrators with the lowest priority value are used while any of them is healthy
+    pub priority: u8,
+    /// Relative share of new streams among concentrators of the same priority
+    pub weight: u32,
+}
+
+impl FromStr for Concentrator {
+    type Err = anyhow::Error;
+
+    /// Parses `addr[,priority=N][,weight=N][,name=NAME]`.
+    fn from_str(s: &str) -> Result<Concentrator> {
+        let mut parts = s.split(',');
+        let addr = parts.next().unwrap_or_default();
+        let mut concentrator = Concentrator {
+            addr: addr
+                .parse()
+                .map_err(|_| anyhow!("invalid concentrator address {}", addr))?,
+            name: None,
+            priority: 0,
+            weight: 1,
+        };
+
+        for part in parts {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| anyhow!("invalid concentrator parameter {}", part))?;
+            match key {
+                "priority" => {
+                    concentrator.priority = value
+                        .parse()
+                        .map_err(|_| anyhow!("invalid concentrator priority {}", value))?
+                }
+                "weight" => {
+                    concentrator.weight = value
+                        .parse()
+                        .map_err(|_| anyhow!("invalid concentrator weight {}", value))?;
+                    if concentrator.weight == 0 {
+                        bail!("concentrator weight must be positive");
+                    }
+                }
+                "name" => concentrator.name = Some(value.to_string()),
+                _ => bail!("unknown concentrator parameter {}", key),
+            }
+        }
+        Ok(concentrator)
+    }
+}
+
+/// How new streams are spread across the healthy concentrators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Strategy {
+    /// In turn, proportionally to their weight.
+    RoundRobin,
+    /// The one with the fewest active streams relative to its weight.
+    LeastStreams,
+    /// The one with the lowest handshake latency.
+    Latency,
+}
+
+impl FromStr for Strategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Strategy> {
+        match s {
+            "round_robin" => Ok(Strategy::RoundRobin),
+            "least_streams" => Ok(Strategy::LeastStreams),
+            "latency" => Ok(Strategy::Latency),
+            _ => bail!("unknown balancing strategy {}", s),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strategy::RoundRobin => write!(f, "round_robin"),
+            Strategy::LeastStreams => write!(f, "least_streams"),
+            Strategy::Latency => write!(f, "latency"),
+        }
+    }
+}
+
+#[derive(Clone)]
+struct Backend {
+    concentrator: Concentrator,
+    pool: Pool,
+}
+
+/// Tunnel chosen for a new stream.
+/// The stream is counted as active on the tunnel until this is dropped.
+pub struct Pick {
+    pub tunnel: Tunnel,
+    pub concentrator: Concentrator,
+    streams: Arc<AtomicUsize>,
+    released: Arc<Notify>,
+}
+
+impl Drop for Pick {
+    fn drop(&mut self) {
+        self.streams.fetch_sub(1, Ordering::Relaxed);
+        self.released.notify();
+    }
+}
+
+/// Spreads new streams across the tunnels of the healthy concentrators
+/// with the best priority, failing over to the next priority when none is healthy.
+#[derive(Clone)]
+pub struct Balancer {
+    strategy: Strategy,
+    backends: Vec<Backend>,
+    /// Shared round-robin position
+    next: Arc<AtomicUsize>,
+}
+
+impl Balancer {
+    pub fn new(strategy: Strategy) -> Balancer {
+        Balancer {
+            strategy,
+            backends: Vec::new(),
+            next: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Add a concentrator and the pool of its quic connections.
+    pub fn add(&mut self, concentrator: Concentrator, pool: Pool) {
+        self.backends.push(Backend { concentrator, pool });
+    }
+
+    /// Pick a tunnel for a new stream, `None` if no concentrator is healthy
+    /// or the connections of the healthy ones all reached their stream cap.
+    pub fn pick(&self) -> Option<Pick> {
+        let healthy: Vec<&Backend> = self
+            .backends
+            .iter()
+            .filter(|b| b.pool.is_healthy())
+            .collect();
+        let priority = healthy.iter().map(|b| b.concentrator.priority).min()?;
+        let candidates: Vec<&Backend> = healthy
+            .into_iter()
+            .filter(|b| b.concentrator.priority == priority)
+            .collect();
+
+        let loads: Vec<Load> = candidates
+            .iter()
+            .map(|b| Load {
+                weight: b.concentrator.weight,
+                streams: b.pool.streams(),
+                latency: b.pool.latency().unwrap_or_default(),
+            })
+            .collect();
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        let chosen = choose(self.strategy, &loads, n);
+
+        // the chosen pool may be full, or have closed since `is_healthy`
+        let order = std::iter::once(chosen).chain((0..candidates.len()).filter(|&i| i != chosen));
+        order.map(|i| candidates[i]).find_map(|backend| {
+            let pick = backend.pool.pick()?;
+            Some(Pick {
+                tunnel: pick.tunnel,
+                concentrator: backend.concentrator.clone(),
+                streams: pick.streams,
+                released: pick.released,
+            })
+        })
+    }
+
+    /// Pick a tunnel, waiting up to `wait` for a concentrator to become healthy
+    /// or for a stream to end.
+    pub async fn wait(&mut self, wait: Duration) -> Option<Pick> {
+        let deadline = Instant::now() + wait;
+        loop {
+            if let Some(pick) = self.pick() {
+                return Some(pick);
+            }
+            if self.backends.is_empty() {
+                return None;
+            }
+            // wait for any tunnel to change or stream to end
+            let released: Vec<Arc<Notify>> =
+                self.backends.iter().map(|b| b.pool.released()).collect();
+            let changed = future::select_all(
+                self.backends
+                    .iter_mut()
+                    .flat_map(|b| b.pool.tunnels())
+                    .map(|t| t.recv().map(|t| t.is_some()).boxed())
+                    .chain(released.iter().map(|r| r.notified().map(|_| true).boxed())),
+            );
+            let changed = time::timeout_at(deadline, changed).await;
+            match changed {
+                Ok((true, ..)) => {}
+                // timed out, or a supervisor is gone
+                _ => return None,
+            }
+        }
+    }
+}
+
+/// State of a candidate concentrator.
+struct Load {
+    weight: u32,
+    streams: usize,
+    latency: Duration,
+}
+
+/// Returns the index of the candidate `strategy` chooses,
+/// `n` is the round-robin position.
+fn choose(strategy: Strategy, candidates: &[Load], n: usize) -> usize {
+    let index = match strategy {
+        Strategy::RoundRobin => {
+            let total: usize = candidates.iter().map(|c| c.weight as usize).sum();
+            let mut n = n % total;
+            candidates.iter().position(|c| {
+                if n < c.weight as usize {
+                    return true;
+                }
+                n -= c.weight as usize;
+                false
+            })
+        }
+        Strategy::LeastStreams => (0..candidates.len()).min_by(|&a, &b| {
+            // compare streams per weight without dividing
+            let (a, b) = (&candidates[a], &candidates[b]);
+            (a.streams * b.weight as usize).cmp(&(b.streams * a.weight as usize))
+        }),
+        Strategy::Latency => (0..candidates.len()).min_by_key(|&i| candidates[i].latency),
+    };
+    // there is at least one candidate with a positive weight
+    index.unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_concentrator_test() {
+        assert_eq!(
+            Concentrator {
+                addr: "203.0.113.1:4433".parse().unwrap(),
+                name: None,
+                priority: 0,
+                weight: 1,
+            },
+            "203.0.113.1:4433".parse().unwrap()
+        );
+        assert_eq!(
+            Concentrator {
+                addr: "[2001:db8::1]:4433".parse().unwrap(),
+                name: Some("eu.example.com".to_string()),
+                priority: 1,
+                weight: 3,
+            },
+            "[2001:db8::1]:4433,priority=1,weight=3,name=eu.example.com"
+                .parse()
+                .unwrap()
+        );
+
+        assert!("example.com:4433".parse::<Concentrator>().is_err());
+        assert!("203.0.113.1:4433,weight=0".parse::<Concentrator>().is_err());
+        assert!("203.0.113.1:4433,region=eu"
+            .parse::<Concentrator>()
+            .is_err());
+    }
+
+    fn load(weight: u32, streams: usize, latency_ms: u64) -> Load {
+        Load {
+            weight,
+            streams,
+            latency: Duration::from_millis(latency_ms),
+        }
+    }
+
+    #[test]
+    fn choose_test() {
+        let candidates = [load(1, 4, 30), load(3, 6, 10), load(1, 3, 20)];
+
+        let picks: Vec<usize> = (0..5)
+            .map(|n| choose(Strategy::RoundRobin, &candidates, n))
+            .collect();
+        assert_eq!(vec![0, 1, 1, 1, 2], picks);
+
+        // 6 streams for a weight of 3 is the least loaded
+        assert_eq!(1, choose(Strategy::LeastStreams, &candidates, 0));
+        assert_eq!(1, choose(Strategy::Latency, &candidates, 0));
+    }
+
+    #[tokio::test]
+    async fn wait_unhealthy_test() {
+        let (_tx, tunnel) = tokio::sync::watch::channel(None);
+        let mut balancer = Balancer::new(Strategy::RoundRobin);
+        let pool = Pool::with_tunnels(vec![tunnel], crate::pool::DEFAULT_STREAM_CAP);
+        balancer.add("203.0.113.1:4433".parse().unwrap(), pool);
+
+        assert!(balancer.pick().is_none());
+        assert!(balancer.wait(Duration::from_millis(10)).await.is_none());
+    }
+}
@@ -1,13 +1,21 @@
 use anyhow::{anyhow, Result};
 use clap::{App, Arg, ArgMatches};
-use std::{fs, net};
+use futures::future;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use tcp_over_quic::auth::Credentials;
+use tcp_over_quic::balancer::{Balancer, Concentrator, Strategy};
 use tcp_over_quic::client;
-use tokio::net::TcpListener;
+use tcp_over_quic::frontend::Frontend;
+use tcp_over_quic::identity;
+use tcp_over_quic::pool::{self, Pool};
+use tcp_over_quic::quic_tunnel::destination::{parse_bind_ip, Destination};
+use tcp_over_quic::quic_tunnel::reverse::Registration;
+use tcp_over_quic::quic_tunnel::version;
 use tokio::signal;
-use tokio::sync::{broadcast, mpsc};
-use tracing::{error, info};
-
-pub const ALPN_QUIC_HTTP: &[&[u8]] = &[b"hq-29"];
+use tokio::time::Duration;
+use tracing::{error, info, warn};
 
 pub fn create_options() -> ArgMatches<'static> {
     App::new("client")
@@ -17,21 +25,103 @@ pub fn create_options() -> ArgMatches<'static> {
             Arg::with_name("tcp_source_port")
                 .long("tcp_source_port")
                 .help("the tcp source port to use for tcp server")
-                .required(true)
+                .required_unless_one(&["forward", "udp_forward", "reverse", "stdio"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bind_ip")
+                .long("bind_ip")
+                .help("address the tcp and udp listeners are bound to, [::] accepts both ipv4 and ipv6, can be repeated")
+                .default_value("127.0.0.1")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("forward")
+                .long("forward")
+                .help("forwarding rule port=host:port, or a port range start-end=host:start-end mapped one-to-one, can be repeated")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("udp_forward")
+                .long("udp_forward")
+                .help("udp forwarding rule port=host:port, or a port range start-end=host:start-end mapped one-to-one, can be repeated")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reverse")
+                .long("reverse")
+                .help("reverse tunnel port=host:port, the concentrator listens on port and forwards its connections to host:port reached from the client, can be repeated")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stdio")
+                .long("stdio")
+                .help("tunnel stdin and stdout to dest instead of listening, e.g. as an ssh ProxyCommand, logs are written to stderr")
+                .requires("dest"),
+        )
+        .arg(
+            Arg::with_name("dest")
+                .long("dest")
+                .help("tcp address or host:port stdin and stdout are tunneled to")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("quic_serv_addr")
                 .long("quic_serv_addr")
-                .help("quic server url where tcp traffic will be forwarded")
+                .help("quic server address where tcp traffic will be forwarded, addr[,priority=N][,weight=N][,name=NAME], can be repeated to fail over and balance across concentrators")
                 .required(true)
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("balance")
+                .long("balance")
+                .help("how new streams are spread across the healthy concentrators of the best priority")
+                .possible_values(&["round_robin", "least_streams", "latency"])
+                .default_value("round_robin")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("handshake_timeout")
+                .long("handshake_timeout")
+                .help("seconds allowed to open a quic stream and then to get the concentrator's reply")
+                .default_value("10")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("pool_size")
+                .long("pool_size")
+                .help("maximum quic connections per concentrator, extra connections are opened once the others carry stream_cap streams")
+                .default_value("4")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stream_cap")
+                .long("stream_cap")
+                .help("streams per quic connection before another connection of the pool is used, 64 by default, must stay below the concentrator's --max_streams_per_connection past which streams wait for stream credit")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("tcp_dest_addr")
                 .long("tcp_dest_addr")
-                .help("tcp address sent to quic server as tcp destination")
-                .required(true)
+                .help("tcp address or host:port sent to quic server as tcp destination, required in forward mode")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mode")
+                .long("mode")
+                .help("how the tcp destination is chosen, forward sends every connection to tcp_dest_addr, socks5 and http let tcp clients request it and transparent uses the original destination of connections redirected by iptables")
+                .possible_values(&["forward", "socks5", "http", "transparent"])
+                .default_value("forward")
                 .takes_value(true),
         )
         .arg(
@@ -41,10 +131,37 @@ pub fn create_options() -> ArgMatches<'static> {
                 .required(true)
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("client_cert")
+                .long("client_cert")
+                .help("certificate chain presented to concentrators requiring client authentication, in PEM or DER format")
+                .requires("client_key")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("client_key")
+                .long("client_key")
+                .help("private key of client_cert, in PEM or DER format")
+                .requires("client_cert")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("auth_token_path")
+                .long("auth_token_path")
+                .help("file of the token to authenticate with to concentrators requiring it")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("quic_serv_name")
                 .long("quic_serv_name")
-                .help("quic server's name used in cert")
+                .help("quic server's name used in cert, for the concentrators without a name= of their own")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tunnel_versions")
+                .long("tunnel_versions")
+                .help("comma separated tunnel protocol versions to offer, most preferred first")
+                .default_value("7,6,5,4,3,2,1")
                 .takes_value(true),
         )
         .get_matches()
@@ -52,71 +169,215 @@ pub fn create_options() -> ArgMatches<'static> {
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+    let matches = create_options();
+
     // enable logging
     // see https://docs.rs/tracing for more info
-    tracing_subscriber::fmt::try_init()?;
-
-    let matches = create_options();
-    let tcp_source_port = matches.value_of("tcp_source_port").unwrap();
-    let quic_serv_addr = matches.value_of("quic_serv_addr").unwrap();
-    let quic_serv_name = matches.value_of("quic_serv_name").unwrap();
+    if matches.is_present("stdio") {
+        // stdout carries the tunneled data
+        tracing_subscriber::fmt()
+            .with_writer(std::io::stderr)
+            .try_init()?;
+    } else {
+        tracing_subscriber::fmt::try_init()?;
+    }
+    let concentrators = matches
+        .values_of("quic_serv_addr")
+        .unwrap()
+        .map(|s| s.parse())
+        .collect::<Result<Vec<Concentrator>>>()?;
+    let strategy: Strategy = matches.value_of("balance").unwrap().parse()?;
+    let handshake_timeout =
+        Duration::from_secs(matches.value_of("handshake_timeout").unwrap().parse()?);
+    let pool_size: usize = matches.value_of("pool_size").unwrap().parse()?;
+    let stream_cap = match matches.value_of("stream_cap") {
+        Some(cap) => {
+            pool::parse_stream_cap(cap).map_err(|e| anyhow!("invalid --stream_cap {}", e))?
+        }
+        None => pool::DEFAULT_STREAM_CAP,
+    };
+    if stream_cap >= pool::CONCENTRATOR_STREAM_WINDOW {
+        warn!(
+            "--stream_cap {} isn't below the default --max_streams_per_connection {} of concentrators, \
+             streams past a concentrator's limit wait for a stream to end instead of using another connection",
+            stream_cap,
+            pool::CONCENTRATOR_STREAM_WINDOW
+        );
+    }
+    let quic_serv_name = matches.value_of("quic_serv_name");
     let ca_path = matches
         .value_of("quic_serv_cert_path")
         .unwrap_or("cert/public_cert.der");
 
-    let tcp_dest_addr: net::SocketAddr = matches
-        .value_of("tcp_dest_addr")
+    let bind_ips = matches
+        .values_of("bind_ip")
         .unwrap()
-        .parse()
-        .expect("invalid tcp destination address");
+        .map(parse_bind_ip)
+        .collect::<Result<Vec<IpAddr>>>()?;
+
+    let mut rules = Vec::new();
+    if let Some(tcp_source_port) = matches.value_of("tcp_source_port") {
+        let frontend = match matches.value_of("mode").unwrap() {
+            "socks5" => Frontend::Socks5,
+            "http" => Frontend::HttpConnect,
+            "transparent" => Frontend::Transparent,
+            _ => {
+                let tcp_dest: Destination = matches
+                    .value_of("tcp_dest_addr")
+                    .ok_or("tcp_dest_addr is required in forward mode")?
+                    .parse()
+                    .expect("invalid tcp destination address");
+                Frontend::Forward(tcp_dest)
+            }
+        };
+        let port: u16 = tcp_source_port.parse()?;
+        for ip in &bind_ips {
+            rules.push(client::Rule {
+                listen_addr: SocketAddr::new(*ip, port),
+                frontend: frontend.clone(),
+            });
+        }
+    }
+    for spec in matches.values_of("forward").into_iter().flatten() {
+        for (port, tcp_dest) in client::parse_forward(spec)? {
+            for ip in &bind_ips {
+                rules.push(client::Rule {
+                    listen_addr: SocketAddr::new(*ip, port),
+                    frontend: Frontend::Forward(tcp_dest.clone()),
+                });
+            }
+        }
+    }
+
+    let mut udp_rules = Vec::new();
+    for spec in matches.values_of("udp_forward").into_iter().flatten() {
+        for (port, udp_dest) in client::parse_forward(spec)? {
+            for ip in &bind_ips {
+                udp_rules.push((SocketAddr::new(*ip, port), udp_dest.clone()));
+            }
+        }
+    }
+
+    let mut reverse_rules = Vec::new();
+    for spec in matches.values_of("reverse").into_iter().flatten() {
+        reverse_rules.extend(client::parse_forward(spec)?);
+    }
+    let reverse = if reverse_rules.is_empty() {
+        None
+    } else {
+        Some(Registration {
+            rules: reverse_rules,
+            handshake_timeout,
+        })
+    };
 
-    let (notify_shutdown, _) = broadcast::channel(1);
-    let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
+    let versions = version::parse_versions(matches.value_of("tunnel_versions").unwrap())?;
+    let credentials = match matches.value_of("auth_token_path") {
+        Some(path) => Some(Credentials::load(path, handshake_timeout)?),
+        None => None,
+    };
 
     // QUIC setup
     let mut endpoint = quinn::Endpoint::builder();
     let mut client_config = quinn::ClientConfigBuilder::default();
 
-    client_config.protocols(ALPN_QUIC_HTTP);
-    client_config.add_certificate_authority(get_certificate(&ca_path).unwrap())?;
-
-    endpoint.default_client_config(client_config.build());
+    client_config.protocols(&version::alpn_protocols(&versions));
+    client_config.add_certificate_authority(get_certificate(ca_path).unwrap())?;
 
-    let (endpoint, _) = endpoint.bind(&"[::]:0".parse().unwrap())?;
+    let mut client_config = client_config.build();
+    if let (Some(cert_path), Some(key_path)) = (
+        matches.value_of("client_cert"),
+        matches.value_of("client_key"),
+    ) {
+        Arc::make_mut(&mut client_config.crypto).set_single_client_cert(
+            identity::load_certs(cert_path)?,
+            identity::load_key(key_path)?,
+        )?;
+    }
 
-    let new_conn = endpoint
-        .connect(&quic_serv_addr.parse().unwrap(), &quic_serv_name)?
-        .await
-        .map_err(|e| format!("failed to open stream: {}", e))?;
+    endpoint.default_client_config(client_config);
 
-    info!("connected to quic server at {}", quic_serv_addr);
+    let (endpoint, _) = endpoint.bind(&"[::]:0".parse().unwrap())?;
 
-    let quinn::NewConnection {
-        connection: conn, ..
-    } = { new_conn };
+    // a supervisor per pooled connection reconnects whenever its quic connection is closed
+    let mut balancer = Balancer::new(strategy);
+    let mut supervisors = Vec::new();
+    for concentrator in concentrators {
+        let name = concentrator
+            .name
+            .as_deref()
+            .or(quic_serv_name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "no server name for concentrator {}, set name= or --quic_serv_name",
+                    concentrator.addr
+                )
+            })?;
+        let (pool, pool_supervisors) = Pool::new(
+            &endpoint,
+            concentrator.addr,
+            name,
+            pool_size,
+            stream_cap,
+            reverse.clone(),
+            credentials.clone(),
+        );
+        supervisors.extend(pool_supervisors);
+        balancer.add(concentrator, pool);
+    }
+    info!("balancing streams with {}", strategy);
 
     // TCP
-    // Bind a TCP listener
-    info!(
-        "accepting inbound tcp connection on port {}",
-        tcp_source_port
-    );
-    info!("remote outgoing tcp destination set to {}", tcp_dest_addr);
-    let listener = TcpListener::bind(&format!("127.0.0.1:{}", tcp_source_port)).await?;
-    // Initialize the listener state
-    let mut server = client::Listener {
-        listener,
-        quic_connection: conn,
-        tcp_dest_addr,
-        notify_shutdown,
-        shutdown_complete_tx,
-        shutdown_complete_rx,
-    };
+    // Bind a TCP listener per rule, all sharing the quic connections
+    let mut forwards = client::ForwardSet::new(balancer.clone(), handshake_timeout);
+    for rule in rules {
+        match &rule.frontend {
+            Frontend::Forward(tcp_dest) => {
+                info!("forwarding {} to {}", rule.listen_addr, tcp_dest)
+            }
+            Frontend::Socks5 => info!("accepting socks5 CONNECT requests on {}", rule.listen_addr),
+            Frontend::HttpConnect => {
+                info!("accepting http CONNECT requests on {}", rule.listen_addr)
+            }
+            Frontend::Transparent => {
+                info!("accepting redirected connections on {}", rule.listen_addr)
+            }
+        }
+        forwards.add(rule).await?;
+    }
+    for (listen_addr, udp_dest) in udp_rules {
+        info!("forwarding udp {} to {}", listen_addr, udp_dest);
+        forwards.add_udp(listen_addr, udp_dest).await?;
+    }
+
+    if matches.is_present("stdio") {
+        let dest: Destination = matches.value_of("dest").unwrap().parse()?;
+        let code = tokio::select! {
+            res = client::run_stdio(balancer, handshake_timeout, dest) => match res {
+                Ok(()) => 0,
+                Err(err) => {
+                    error!(cause = % err, "stdio tunnel failed");
+                    1
+                }
+            },
+            res = future::try_join_all(supervisors.iter_mut().map(|s| s.run())) => {
+                if let Err(err) = res {
+                    error!(cause = % err, "failed to connect to quic server");
+                }
+                1
+            }
+        };
+        forwards.shutdown().await;
+        endpoint.close(quinn::VarInt::from_u32(0), b"stdio closed");
+        endpoint.wait_idle().await;
+        // a blocking read of stdin would keep the runtime from shutting down
+        std::process::exit(code);
+    }
 
     tokio::select! {
-        res = server.run() => {
+        res = future::try_join_all(supervisors.iter_mut().map(|s| s.run())) => {
             if let Err(err) = res {
-                error!(cause = % err, "failed to accept");
+                error!(cause = % err, "failed to connect to quic server");
             }
         }
         _ = signal::ctrl_c() => {
@@ -124,20 +385,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
             info!("shutting down");
         }
     }
-
-    // Extract the `shutdown_complete` receiver and transmitter
-    let client::Listener {
-        mut shutdown_complete_rx,
-        shutdown_complete_tx,
-        notify_shutdown,
-        ..
-    } = server;
-
-    // drop notify_shutdown to indicate shutdown
-    drop(notify_shutdown);
-    // drop own shutdown_complete_tx and wait for others
-    drop(shutdown_complete_tx);
-    let _ = shutdown_complete_rx.recv().await;
+    forwards.shutdown().await;
 
     Ok(())
 }
@@ -145,22 +393,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
 fn get_certificate(cert_path: &str) -> Result<quinn::Certificate> {
     let cert = fs::read(cert_path)?;
 
-    let certificate: Option<quinn::Certificate>;
-
     if cert.is_ascii() {
         let cert = quinn::CertificateChain::from_pem(&cert)?
             .iter()
             .next()
-            .unwrap()
+            .ok_or_else(|| anyhow!("no certificate in {}", cert_path))?
             .clone();
-        certificate = Some(quinn::Certificate::from(cert))
+        Ok(quinn::Certificate::from(cert))
     } else {
-        certificate = Some(quinn::Certificate::from_der(&cert)?)
-    };
-
-    if certificate.is_some() {
-        return Ok(certificate.unwrap());
-    };
-
-    Err(anyhow!("unable to parse cert"))
+        Ok(quinn::Certificate::from_der(&cert)?)
+    }
 }
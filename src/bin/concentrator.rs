@@ -2,15 +2,27 @@ use anyhow::{anyhow, Result};
 use clap::{App, Arg, ArgMatches};
 use core::time::Duration;
 use quinn::{Certificate, CertificateChain, PrivateKey};
+use std::collections::HashMap;
 use std::fs;
+use std::net::{IpAddr, SocketAddr};
 use std::path::Path;
 use std::sync::Arc;
+use tcp_over_quic::acl::{Acl, Rule};
+use tcp_over_quic::auth::TokenFile;
 use tcp_over_quic::concentrator;
+use tcp_over_quic::identity;
+use tcp_over_quic::quic_tunnel::admission::{self, Admission, Limits};
+use tcp_over_quic::quic_tunnel::destination::{parse_bind_ip, parse_port_range};
+use tcp_over_quic::quic_tunnel::ratelimit::{RateLimits, Rates};
+use tcp_over_quic::quic_tunnel::reverse;
+use tcp_over_quic::quic_tunnel::sockopt;
+use tcp_over_quic::quic_tunnel::version;
 use tokio::signal;
-use tokio::sync::{broadcast, mpsc};
-use tracing::{error, info};
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tracing::{error, info, warn};
 
-pub const ALPN_QUIC_HTTP: &[&[u8]] = &[b"hq-29"];
+/// Open sockets towards destinations when the file descriptor limit is unknown
+const DEFAULT_BACKEND_SOCKETS: usize = 4096;
 
 pub fn create_options() -> ArgMatches<'static> {
     App::new("concentrator")
@@ -22,6 +34,15 @@ pub fn create_options() -> ArgMatches<'static> {
                 .help("quic server port address to listen on")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("bind_ip")
+                .long("bind_ip")
+                .help("address the quic endpoint is bound to, [::] accepts both ipv4 and ipv6, can be repeated")
+                .default_value("127.0.0.1")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("quic_serv_cert_path")
                 .long("quic_serv_cert_path")
@@ -34,6 +55,161 @@ pub fn create_options() -> ArgMatches<'static> {
                 .help("quic server cert private key, in PEM format")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("tunnel_versions")
+                .long("tunnel_versions")
+                .help("comma separated tunnel protocol versions to accept, most preferred first")
+                .default_value("7,6,5,4,3,2,1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("handshake_timeout")
+                .long("handshake_timeout")
+                .help("seconds allowed to complete the quic handshake, and then to receive a stream's TLVs")
+                .default_value("10")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_pending_handshakes")
+                .long("max_pending_handshakes")
+                .help("quic handshakes in progress above which new connections are refused")
+                .default_value("128")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_connections")
+                .long("max_connections")
+                .help("quic connections of all clients above which new connections are refused")
+                .default_value("1024")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_streams_per_connection")
+                .long("max_streams_per_connection")
+                .help("streams a quic connection may have open at once, clients wait for one to end before opening another")
+                .default_value("256")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_pending_dials")
+                .long("max_pending_dials")
+                .help("outbound connections in progress above which new streams are refused")
+                .default_value("256")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_pending_dials_per_dest")
+                .long("max_pending_dials_per_dest")
+                .help("outbound connections in progress to a destination above which new streams to it are refused")
+                .default_value("32")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_backend_sockets")
+                .long("max_backend_sockets")
+                .help("open sockets towards destinations above which new streams are refused, half the file descriptor limit by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("udp_idle_timeout")
+                .long("udp_idle_timeout")
+                .help("seconds after which udp flows without traffic are closed")
+                .default_value("60")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reverse_ports")
+                .long("reverse_ports")
+                .help("port range start-end clients may listen on for reverse tunnels, which are disabled by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("reverse_bind_ip")
+                .long("reverse_bind_ip")
+                .help("address the listeners of reverse tunnels are bound to")
+                .default_value("127.0.0.1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("allow")
+                .long("allow")
+                .help("destinations clients may connect to as ip[/prefix][:ports], the others are then denied, e.g. 10.0.0.0/8:5432")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("identity_allow")
+                .long("identity_allow")
+                .help("destinations the clients authenticated as identity may connect to as identity=ip[/prefix][:ports], in place of --allow")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("client_ca_path")
+                .long("client_ca_path")
+                .help("CA certificates, in PEM or DER format, clients must present a certificate signed by, clients aren't authenticated without it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("client_identity")
+                .long("client_identity")
+                .help("identity of the client certificates: any for the first dns, uri or email subject alternative name else the common name, or only dns, uri, email or cn")
+                .possible_values(&["any", "dns", "uri", "email", "cn"])
+                .default_value("any")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("global_upload_limit")
+                .long("global_upload_limit")
+                .help("bytes per second from clients to their destinations of all clients with an optional K, M or G suffix, unlimited by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("global_download_limit")
+                .long("global_download_limit")
+                .help("bytes per second from destinations to the clients of all clients with an optional K, M or G suffix, unlimited by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("client_upload_limit")
+                .long("client_upload_limit")
+                .help("bytes per second from clients to their destinations of each client, identified by its identity or else its address, with an optional K, M or G suffix, unlimited by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("client_download_limit")
+                .long("client_download_limit")
+                .help("bytes per second from destinations to the clients of each client, identified by its identity or else its address, with an optional K, M or G suffix, unlimited by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stream_upload_limit")
+                .long("stream_upload_limit")
+                .help("bytes per second from clients to their destinations of each stream with an optional K, M or G suffix, unlimited by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("stream_download_limit")
+                .long("stream_download_limit")
+                .help("bytes per second from destinations to the clients of each stream with an optional K, M or G suffix, unlimited by default")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("auth_tokens_path")
+                .long("auth_tokens_path")
+                .help("file of the tokens clients must authenticate with, one `name sha256 expires [rule,...]` per line, clients aren't required to authenticate without it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("deny")
+                .long("deny")
+                .help("destinations clients may not connect to as ip[/prefix][:ports], on top of loopback, link-local, multicast and local addresses")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
         .get_matches()
 }
 
@@ -45,7 +221,15 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let matches = create_options();
 
     // setup configs
-    let quic_serv_port = matches.value_of("quic_serv_port").unwrap_or("4433");
+    let quic_serv_port: u16 = matches
+        .value_of("quic_serv_port")
+        .unwrap_or("4433")
+        .parse()?;
+    let bind_ips = matches
+        .values_of("bind_ip")
+        .unwrap()
+        .map(parse_bind_ip)
+        .collect::<Result<Vec<IpAddr>>>()?;
     let quic_serv_cert_path = matches
         .value_of("quic_serv_cert_path")
         .unwrap_or("./cert/cert.pem");
@@ -53,6 +237,68 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
         .value_of("quic_serv_key_path")
         .unwrap_or("./cert/key.pem");
 
+    let versions = version::parse_versions(matches.value_of("tunnel_versions").unwrap())?;
+    let handshake_timeout =
+        Duration::from_secs(matches.value_of("handshake_timeout").unwrap().parse()?);
+    let udp_idle_timeout =
+        Duration::from_secs(matches.value_of("udp_idle_timeout").unwrap().parse()?);
+    let max_pending_handshakes = parse_limit(&matches, "max_pending_handshakes")?;
+    let limits = Limits {
+        connections: parse_limit(&matches, "max_connections")?,
+        streams_per_connection: parse_limit(&matches, "max_streams_per_connection")?,
+        pending_dials: parse_limit(&matches, "max_pending_dials")?,
+        pending_dials_per_dest: parse_limit(&matches, "max_pending_dials_per_dest")?,
+        backend_sockets: match matches.value_of("max_backend_sockets") {
+            Some(_) => parse_limit(&matches, "max_backend_sockets")?,
+            // leave room for the quic endpoint and the rest of the process,
+            // the limit is RLIM_INFINITY when unlimited
+            None => match sockopt::fd_limit() {
+                Ok(limit) => (limit / 2).min(admission::MAX_LIMIT as u64) as usize,
+                Err(e) => {
+                    warn!("unable to read the file descriptor limit {}", e);
+                    DEFAULT_BACKEND_SOCKETS
+                }
+            },
+        },
+    };
+
+    let reverse = match matches.value_of("reverse_ports") {
+        Some(ports) => {
+            let (start, end) = parse_port_range(ports)?;
+            let bind_ip = parse_bind_ip(matches.value_of("reverse_bind_ip").unwrap())?;
+            info!("accepting reverse tunnels on {} ports {}", bind_ip, ports);
+            Some(Arc::new(reverse::Config {
+                ports: start..=end,
+                bind_ip,
+            }))
+        }
+        None => None,
+    };
+
+    let local_addrs = sockopt::local_addrs().unwrap_or_else(|e| {
+        warn!("unable to list local addresses {}", e);
+        Vec::new()
+    });
+    let acl = Acl {
+        allow: parse_rules(&matches, "allow")?,
+        deny: parse_rules(&matches, "deny")?,
+        local_addrs,
+        identity_allow: parse_identity_rules(&matches)?,
+    };
+
+    let tokens = match matches.value_of("auth_tokens_path") {
+        Some(path) => {
+            info!("requiring clients to authenticate with a token of {}", path);
+            Some(Arc::new(TokenFile::open(path)?))
+        }
+        None => None,
+    };
+
+    let rate_limits = RateLimits::new(
+        parse_rates(&matches, "upload")?,
+        parse_rates(&matches, "download")?,
+    );
+
     let (notify_shutdown, _) = broadcast::channel(1);
     let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
 
@@ -69,6 +315,8 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     let mut transport_config = quinn::TransportConfig::default();
     transport_config
         .stream_window_uni(0)
+        // clients wait for a stream to end before opening another one
+        .stream_window_bidi(limits.streams_per_connection as u64)
         // keep client connection alive package
         // default max idle timeout is 10sec and cant be modified
         .keep_alive_interval(Some(Duration::from_secs(5)));
@@ -77,20 +325,38 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     server_config.transport = Arc::new(transport_config);
     let mut server_config = quinn::ServerConfigBuilder::new(server_config);
 
-    server_config.protocols(ALPN_QUIC_HTTP);
+    server_config.protocols(&version::alpn_protocols(&versions));
     server_config.certificate(CertificateChain::from_certs(vec![certificate]), key)?;
+    let mut server_config = server_config.build();
+    if let Some(ca_path) = matches.value_of("client_ca_path") {
+        identity::require_client_certs(Arc::make_mut(&mut server_config.crypto), ca_path)?;
+        info!("requiring client certificates signed by {}", ca_path);
+    }
 
-    let mut endpoint = quinn::Endpoint::builder();
-    endpoint.listen(server_config.build());
-
-    let (_, incoming) = endpoint
-        .bind(&format!("127.0.0.1:{}", quic_serv_port).parse()?)
-        .expect(&format!("unable to bind to local port {}", quic_serv_port));
-
-    info!("listening for quic stream on {}", quic_serv_port);
+    // an endpoint per bind address, their connections are accepted together
+    let mut incoming = Vec::new();
+    for ip in bind_ips {
+        let addr = SocketAddr::new(ip, quic_serv_port);
+        let socket =
+            sockopt::udp_socket(addr).map_err(|e| anyhow!("unable to bind to {} {}", addr, e))?;
+        let mut endpoint = quinn::Endpoint::builder();
+        endpoint.listen(server_config.clone());
+        let (endpoint, endpoint_incoming) = endpoint.with_socket(socket)?;
+        info!("listening for quic stream on {}", endpoint.local_addr()?);
+        incoming.push(endpoint_incoming);
+    }
 
     let mut server = concentrator::Listener {
-        incoming,
+        incoming: futures::stream::select_all(incoming),
+        handshake_timeout,
+        pending_handshakes: Arc::new(Semaphore::new(max_pending_handshakes)),
+        reverse,
+        udp_idle_timeout,
+        acl: Arc::new(acl),
+        identity_source: matches.value_of("client_identity").unwrap().parse()?,
+        tokens,
+        rate_limits: Arc::new(rate_limits),
+        admission: Arc::new(Admission::new(limits)),
         notify_shutdown,
         shutdown_complete_tx,
         shutdown_complete_rx,
@@ -126,37 +392,70 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     Ok(())
 }
 
+fn parse_limit(matches: &ArgMatches, name: &str) -> Result<usize> {
+    let value = matches.value_of(name).unwrap();
+    admission::parse_limit(value).map_err(|e| anyhow!("invalid --{} {}", name, e))
+}
+
+fn parse_rules(matches: &ArgMatches, name: &str) -> Result<Vec<Rule>> {
+    matches
+        .values_of(name)
+        .map(|values| values.map(str::parse).collect())
+        .unwrap_or_else(|| Ok(Vec::new()))
+}
+
+fn parse_rates(matches: &ArgMatches, direction: &str) -> Result<Rates> {
+    let rate = |scope: &str| {
+        matches
+            .value_of(format!("{}_{}_limit", scope, direction))
+            .map(str::parse)
+            .transpose()
+    };
+    Ok(Rates {
+        global: rate("global")?,
+        client: rate("client")?,
+        stream: rate("stream")?,
+    })
+}
+
+fn parse_identity_rules(matches: &ArgMatches) -> Result<HashMap<String, Vec<Rule>>> {
+    let mut rules: HashMap<String, Vec<Rule>> = HashMap::new();
+    for spec in matches.values_of("identity_allow").into_iter().flatten() {
+        let (identity, rule) = spec
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected identity=rule, got {}", spec))?;
+        rules
+            .entry(identity.to_string())
+            .or_default()
+            .push(rule.parse()?);
+    }
+    Ok(rules)
+}
+
 fn get_certificate(cert_path: &str, key_path: &str) -> Result<(Certificate, PrivateKey)> {
     let key = &fs::read(key_path)?;
     let cert = &fs::read(cert_path)?;
 
-    let private_key: Option<PrivateKey>;
-    let certificate: Option<Certificate>;
-
     // check for certificate
-    if cert.is_ascii() {
-        let cert = quinn::CertificateChain::from_pem(&cert)?
+    let certificate = if cert.is_ascii() {
+        let cert = quinn::CertificateChain::from_pem(cert)?
             .iter()
             .next()
-            .unwrap()
+            .ok_or_else(|| anyhow!("no certificate in {}", cert_path))?
             .clone();
-        certificate = Some(quinn::Certificate::from(cert))
+        quinn::Certificate::from(cert)
     } else {
-        certificate = Some(quinn::Certificate::from_der(&cert)?)
+        quinn::Certificate::from_der(cert)?
     };
 
     // check for key
-    if key.is_ascii() {
-        private_key = Some(quinn::PrivateKey::from_pem(key)?)
+    let private_key = if key.is_ascii() {
+        quinn::PrivateKey::from_pem(key)?
     } else {
-        private_key = Some(quinn::PrivateKey::from_der(key)?)
-    }
-
-    if certificate.is_some() && private_key.is_some() {
-        return Ok((certificate.unwrap(), private_key.unwrap()));
-    }
+        quinn::PrivateKey::from_der(key)?
+    };
 
-    Err(anyhow!("unable to parse cert or private key"))
+    Ok((certificate, private_key))
 }
 
 fn generate_self_signed_cert() -> Result<(Certificate, PrivateKey)> {
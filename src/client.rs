@@ -1,57 +1,299 @@
-use crate::quic_tunnel::connection;
+use crate::balancer::{Balancer, Pick};
+use crate::frontend::{self, Frontend};
+use crate::quic_tunnel::connection::{self, connect_tlv, read_tlv};
+use crate::quic_tunnel::destination::{parse_port_range, Destination};
+use crate::quic_tunnel::ratelimit::ClientLimits;
+use crate::quic_tunnel::sockopt;
+use crate::quic_tunnel::tlv::{self, Tlv};
+use crate::quic_tunnel::udp::{self, FLOW_QUEUE};
 use crate::Shutdown;
-use anyhow::Result;
+use anyhow::{anyhow, bail, ensure, Result};
+use bytes::{Bytes, BytesMut};
+use futures::future;
+use quinn::{RecvStream, SendStream};
+use std::collections::HashMap;
 use std::net::SocketAddr;
-use tokio::net::{TcpListener, TcpStream};
+use std::sync::Arc;
+use tokio::io::{self, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::{broadcast, mpsc};
+use tokio::task::JoinHandle;
 use tokio::time::{self, Duration};
-use tracing::{error, trace};
+use tracing::{debug, error, info, trace};
+
+/// How long accepted connections wait for the quic connection to be reestablished.
+const RECONNECT_WAIT: Duration = Duration::from_secs(10);
+
+// stdio payload size based on 1500 MTU
+const STDIO_BUF_SIZE: usize = 1480;
+
+/// A tcp listen address and how the destination of its connections is chosen.
+#[derive(Debug, Clone)]
+pub struct Rule {
+    pub listen_addr: SocketAddr,
+    pub frontend: Frontend,
+}
+
+/// Set of forwarding rules sharing the quic connections to the concentrators.
+/// Every rule has its own accept loop and can be removed on its own.
+pub struct ForwardSet {
+    balancer: Balancer,
+
+    handshake_timeout: Duration,
+
+    /// Broadcasts a shutdown signal to the accept loop and connections of each rule.
+    rules: HashMap<SocketAddr, (broadcast::Sender<()>, JoinHandle<()>)>,
+
+    /// Same for the receive loop and flows of each udp rule.
+    udp_rules: HashMap<SocketAddr, (broadcast::Sender<()>, JoinHandle<()>)>,
+
+    /// Used as part of the graceful shutdown process to wait for client
+    /// connections of all rules to complete processing.
+    shutdown_complete_rx: mpsc::Receiver<()>,
+    shutdown_complete_tx: mpsc::Sender<()>,
+}
+
+impl ForwardSet {
+    pub fn new(balancer: Balancer, handshake_timeout: Duration) -> ForwardSet {
+        let (shutdown_complete_tx, shutdown_complete_rx) = mpsc::channel(1);
+        ForwardSet {
+            balancer,
+            handshake_timeout,
+            rules: HashMap::new(),
+            udp_rules: HashMap::new(),
+            shutdown_complete_rx,
+            shutdown_complete_tx,
+        }
+    }
+
+    /// Bind the rule's listener and start accepting connections.
+    /// Returns the bound address, which removes the rule.
+    pub async fn add(&mut self, rule: Rule) -> Result<SocketAddr> {
+        ensure!(
+            !self.rules.contains_key(&rule.listen_addr),
+            "duplicate rule for {}",
+            rule.listen_addr
+        );
+
+        let listener = match rule.frontend {
+            Frontend::Transparent => {
+                TcpListener::from_std(sockopt::transparent_listener(rule.listen_addr)?)?
+            }
+            _ => TcpListener::from_std(sockopt::tcp_listener(rule.listen_addr)?)?,
+        };
+        let listen_addr = listener.local_addr()?;
+
+        // subscribe before spawning so a rule removed right away is signaled
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let shutdown = Shutdown::new(notify_shutdown.subscribe());
+        let mut listener = Listener {
+            listener,
+            balancer: self.balancer.clone(),
+            handshake_timeout: self.handshake_timeout,
+            frontend: rule.frontend,
+            notify_shutdown: notify_shutdown.clone(),
+            shutdown_complete_tx: self.shutdown_complete_tx.clone(),
+        };
+        let task = tokio::spawn(async move {
+            if let Err(err) = listener.run(shutdown).await {
+                error!(cause = % err, "failed to accept on {}", listen_addr);
+            }
+        });
+
+        self.rules.insert(listen_addr, (notify_shutdown, task));
+        Ok(listen_addr)
+    }
+
+    /// Bind a udp socket on `listen_addr` and forward its datagrams to `dest`,
+    /// with a udp flow per source address.
+    /// Returns the bound address, which removes the rule.
+    pub async fn add_udp(
+        &mut self,
+        listen_addr: SocketAddr,
+        dest: Destination,
+    ) -> Result<SocketAddr> {
+        ensure!(
+            !self.udp_rules.contains_key(&listen_addr),
+            "duplicate udp rule for {}",
+            listen_addr
+        );
+
+        let socket = UdpSocket::from_std(sockopt::udp_socket(listen_addr)?)?;
+        let local_addr = socket.local_addr()?;
+
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let shutdown = Shutdown::new(notify_shutdown.subscribe());
+        let mut listener = UdpListener {
+            socket: Arc::new(socket),
+            balancer: self.balancer.clone(),
+            handshake_timeout: self.handshake_timeout,
+            dest,
+            notify_shutdown: notify_shutdown.clone(),
+            shutdown_complete_tx: self.shutdown_complete_tx.clone(),
+        };
+        let task = tokio::spawn(async move {
+            if let Err(err) = listener.run(shutdown).await {
+                error!(cause = % err, "failed to receive on {}", local_addr);
+            }
+        });
+
+        self.udp_rules.insert(local_addr, (notify_shutdown, task));
+        Ok(local_addr)
+    }
+
+    /// Stop accepting on `listen_addr` and shut its connections down.
+    /// Returns `false` if there is no such rule.
+    pub async fn remove(&mut self, listen_addr: SocketAddr) -> bool {
+        match self.rules.remove(&listen_addr) {
+            Some(rule) => {
+                stop(rule).await;
+                info!("stopped forwarding {}", listen_addr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop receiving on `listen_addr` and close its udp flows.
+    /// Returns `false` if there is no such udp rule.
+    pub async fn remove_udp(&mut self, listen_addr: SocketAddr) -> bool {
+        match self.udp_rules.remove(&listen_addr) {
+            Some(rule) => {
+                stop(rule).await;
+                info!("stopped forwarding udp {}", listen_addr);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove all rules and wait for their connections to complete.
+    pub async fn shutdown(mut self) {
+        let addrs: Vec<SocketAddr> = self.rules.keys().cloned().collect();
+        for addr in addrs {
+            self.remove(addr).await;
+        }
+        let addrs: Vec<SocketAddr> = self.udp_rules.keys().cloned().collect();
+        for addr in addrs {
+            self.remove_udp(addr).await;
+        }
+
+        // drop own shutdown_complete_tx and wait for others
+        drop(self.shutdown_complete_tx);
+        let _ = self.shutdown_complete_rx.recv().await;
+    }
+}
+
+/// Signal a rule's task to shut down and wait for it.
+async fn stop((notify_shutdown, task): (broadcast::Sender<()>, JoinHandle<()>)) {
+    // the task subscribed when the rule was added,
+    // so the signal is received even if it hasn't started yet
+    let _ = notify_shutdown.send(());
+    let _ = task.await;
+}
+
+/// Parse a forwarding rule `listen=destination`, where `listen` is a port or
+/// a port range mapped one-to-one to the destination's port range,
+/// e.g. `6970=db.internal:5432` or `7000-7009=10.0.0.1:8000-8009`.
+pub fn parse_forward(spec: &str) -> Result<Vec<(u16, Destination)>> {
+    let (listen, dest) = spec
+        .split_once('=')
+        .ok_or_else(|| anyhow!("missing destination in forward rule {}", spec))?;
+    let (listen_start, listen_end) = parse_port_range(listen)?;
+
+    // the destination port range is the last component, after any IPv6 brackets
+    let (host, dest_ports) = dest
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("missing port in forward rule {}", spec))?;
+    let (dest_start, dest_end) = parse_port_range(dest_ports)?;
+
+    if listen_end - listen_start != dest_end - dest_start {
+        bail!("port ranges of forward rule {} differ in size", spec);
+    }
+
+    (0..=listen_end - listen_start)
+        .map(|i| {
+            let dest = format!("{}:{}", host, dest_start + i).parse()?;
+            Ok((listen_start + i, dest))
+        })
+        .collect()
+}
 
 /// TCP Server listener state.
 /// which performs the TCP listening and initialization of per-connection state.
 pub struct Listener {
     pub listener: TcpListener,
 
-    pub quic_connection: quinn::Connection,
+    /// Chooses the concentrator's quic connection each stream is opened on
+    pub balancer: Balancer,
 
-    pub tcp_dest_addr: SocketAddr,
+    /// Time allowed to open the quic stream, and then to complete the TLV handshake
+    pub handshake_timeout: Duration,
 
-    /// Broadcasts a shutdown signal to all active connections.
+    /// How the destination of accepted connections is chosen
+    pub frontend: Frontend,
+
+    /// Broadcasts a shutdown signal to the accept loop and all active connections.
     pub notify_shutdown: broadcast::Sender<()>,
 
     /// Used as part of the graceful shutdown process to wait for client
     /// connections to complete processing.
-    pub shutdown_complete_rx: mpsc::Receiver<()>,
     pub shutdown_complete_tx: mpsc::Sender<()>,
 }
 
 impl Listener {
-    /// Run the server
-    pub async fn run(&mut self) -> Result<()> {
+    /// Run the server until the shutdown signal is received
+    pub async fn run(&mut self, mut shutdown: Shutdown) -> Result<()> {
         loop {
-            let socket = self.accept().await?;
-
-            trace!(
-                "new connection accepted opening quic stream {:?}",
-                socket.local_addr()
-            );
+            let socket = tokio::select! {
+                res = self.accept() => res?,
+                _ = shutdown.recv() => return Ok(()),
+            };
 
-            let (quic_send, quic_recv) = self.quic_connection.open_bi().await?;
+            trace!("new connection accepted {:?}", socket.local_addr());
 
-            // Create the necessary per-connection handler state.
-            let mut conn = connection::Connection {
-                shutdown: Shutdown::new(self.notify_shutdown.subscribe()),
-                _shutdown_complete: self.shutdown_complete_tx.clone(),
-            };
-            let addr = self.tcp_dest_addr;
-            // Spawn a new task to process each stream.
+            let mut balancer = self.balancer.clone();
+            let handshake_timeout = self.handshake_timeout;
+            let frontend = self.frontend.clone();
+            let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+            // Spawn a new task to open the stream and process it,
+            // failures only close this tcp connection.
             tokio::spawn(async move {
+                let (pick, quic_send, quic_recv) =
+                    match open_stream(&mut balancer, handshake_timeout).await {
+                        Ok(opened) => opened,
+                        Err(err) => {
+                            error!("{}, resetting tcp connection", err);
+                            if let Err(err) = frontend::reset(socket) {
+                                debug!("unable to reset tcp connection {}", err);
+                            }
+                            return;
+                        }
+                    };
+
+                // Create the necessary per-connection handler state.
+                let mut conn = connection::Connection {
+                    version: pick.tunnel.version,
+                    handshake_timeout,
+                    reverse: None,
+                    reverse_dests: None,
+                    udp: None,
+                    acl: None,
+                    limits: ClientLimits::default(),
+                    admission: None,
+                    shutdown,
+                    _shutdown_complete: shutdown_complete,
+                };
                 if let Err(err) = conn
-                    .run_client_conn(addr, socket, quic_send, quic_recv)
+                    .run_client_conn(frontend, socket, quic_send, quic_recv)
                     .await
                 {
                     error!(cause = ? err, "stream error");
                 }
+                // the stream is no longer active on the concentrator
+                drop(pick);
             });
         }
     }
@@ -79,3 +321,322 @@ impl Listener {
         }
     }
 }
+
+/// Udp listener state, datagrams of each source address are carried in their own flow.
+pub struct UdpListener {
+    /// Shared with the flows which send the replies
+    pub socket: Arc<UdpSocket>,
+
+    /// Chooses the concentrator's quic connection each flow is opened on
+    pub balancer: Balancer,
+
+    /// Time allowed to open the quic stream of a flow, and then to get its identifier
+    pub handshake_timeout: Duration,
+
+    pub dest: Destination,
+
+    /// Broadcasts a shutdown signal to the receive loop and all active flows.
+    pub notify_shutdown: broadcast::Sender<()>,
+
+    /// Used as part of the graceful shutdown process to wait for
+    /// flows to complete processing.
+    pub shutdown_complete_tx: mpsc::Sender<()>,
+}
+
+impl UdpListener {
+    /// Run the listener until the shutdown signal is received
+    pub async fn run(&mut self, mut shutdown: Shutdown) -> Result<()> {
+        // the flow of each source address, numbered so a closed flow
+        // doesn't remove the one which replaced it
+        let mut flows: HashMap<SocketAddr, (u64, mpsc::Sender<Bytes>)> = HashMap::new();
+        let mut next_flow = 0;
+        let (closed_tx, mut closed_rx) = mpsc::unbounded_channel();
+
+        let mut buf = vec![0; u16::MAX as usize];
+        loop {
+            tokio::select! {
+                received = udp::recv_from(&self.socket, &mut buf) => {
+                    let (n, source) = match received {
+                        Ok(received) => received,
+                        Err(e) => {
+                            debug!("error receiving udp datagram {}", e);
+                            continue;
+                        }
+                    };
+                    let mut payload = Bytes::copy_from_slice(&buf[..n]);
+
+                    if let Some((_, tx)) = flows.get_mut(&source) {
+                        match tx.try_send(payload) {
+                            Ok(()) => continue,
+                            Err(TrySendError::Full(_)) => {
+                                debug!("dropping datagram of busy udp flow from {}", source);
+                                continue;
+                            }
+                            // the flow expired, open a new one
+                            Err(TrySendError::Closed(p)) => payload = p,
+                        }
+                    }
+
+                    let (mut tx, rx) = mpsc::channel(FLOW_QUEUE);
+                    let _ = tx.try_send(payload);
+                    flows.insert(source, (next_flow, tx));
+
+                    let flow = next_flow;
+                    next_flow += 1;
+                    let socket = self.socket.clone();
+                    let mut balancer = self.balancer.clone();
+                    let handshake_timeout = self.handshake_timeout;
+                    let dest = self.dest.clone();
+                    let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+                    let shutdown_complete = self.shutdown_complete_tx.clone();
+                    let closed_tx = closed_tx.clone();
+                    // Spawn a new task to open the flow and relay its datagrams.
+                    tokio::spawn(async move {
+                        if let Err(err) = run_udp_flow(
+                            socket, source, dest, &mut balancer, handshake_timeout, rx, shutdown,
+                        ).await {
+                            error!("udp flow from {} failed {}", source, err);
+                        }
+                        let _ = closed_tx.send((source, flow));
+                        drop(shutdown_complete);
+                    });
+                }
+
+                Some((source, flow)) = closed_rx.recv() => {
+                    if flows.get(&source).map(|(f, _)| *f) == Some(flow) {
+                        flows.remove(&source);
+                    }
+                }
+
+                _ = shutdown.recv() => return Ok(()),
+            }
+        }
+    }
+}
+
+/// Open a udp flow to `dest` and relay the datagrams of `source` until
+/// either side closes its stream or the flow is idle.
+async fn run_udp_flow(
+    socket: Arc<UdpSocket>,
+    source: SocketAddr,
+    dest: Destination,
+    balancer: &mut Balancer,
+    handshake_timeout: Duration,
+    mut from_source: mpsc::Receiver<Bytes>,
+    mut shutdown: Shutdown,
+) -> Result<()> {
+    let (pick, mut quic_send, mut quic_recv) = open_stream(balancer, handshake_timeout).await?;
+    let version = pick.tunnel.version;
+    ensure!(
+        version.supports_udp(),
+        "{} doesn't carry udp flows",
+        version
+    );
+
+    let mut buf = BytesMut::new();
+    Tlv::UdpAssociate.encode(&mut buf);
+    connect_tlv(version, &dest, Vec::new())
+        .await?
+        .encode(&mut buf);
+    Tlv::End.encode(&mut buf);
+    quic_send.write_all(&buf).await?;
+
+    let mut decoder = tlv::Decoder::new();
+    let reply = time::timeout(handshake_timeout, read_tlv(&mut quic_recv, &mut decoder))
+        .await
+        .map_err(|_| anyhow!("timed out waiting for UDP Flow TLV"))??;
+    let id = match reply {
+        Some(Tlv::UdpFlow(id)) => id,
+        Some(Tlv::Error(e)) => return Err(e.into()),
+        Some(t) => bail!("expected UDP Flow TLV received {:?}", t),
+        None => bail!("quic stream finished"),
+    };
+
+    let flows = pick.tunnel.flows.clone();
+    let mut from_tunnel = flows.insert(id);
+    debug!("udp flow {} from {} to {} opened", id, source, dest);
+
+    let mut quic_buf = [0; 64];
+    loop {
+        tokio::select! {
+            Some(payload) = from_source.recv() => flows.send(id, &payload),
+            Some(payload) = from_tunnel.recv() => {
+                if let Err(e) = udp::send_to(&socket, &payload, &source).await {
+                    debug!("error sending udp datagram to {} {}", source, e);
+                }
+            }
+
+            // the concentrator closes the stream when the flow expires
+            read = quic_recv.read(&mut quic_buf) => {
+                if let Ok(Some(_)) = read {
+                    continue;
+                }
+                break;
+            }
+
+            _ = time::delay_for(flows.idle_timeout) => break,
+            _ = shutdown.recv() => break,
+        }
+    }
+
+    debug!("udp flow {} from {} closed", id, source);
+    flows.remove(id);
+    let _ = quic_send.finish().await;
+    // the flow is no longer active on the concentrator
+    drop(pick);
+    Ok(())
+}
+
+/// Tunnel stdin and stdout to `dest` over a single stream, e.g. as an ssh ProxyCommand.
+///
+/// The end of stdin is sent to the concentrator which shuts its tcp connection
+/// down for writing. Returns once the concentrator finishes the stream, whether
+/// or not stdin reached its end, or when either side fails. The caller then
+/// exits, closing stdout so its reader sees the end of the connection.
+pub async fn run_stdio(
+    mut balancer: Balancer,
+    handshake_timeout: Duration,
+    dest: Destination,
+) -> Result<()> {
+    let (pick, mut quic_send, mut quic_recv) =
+        open_stream(&mut balancer, handshake_timeout).await?;
+    let version = pick.tunnel.version;
+
+    let mut buf = BytesMut::new();
+    connect_tlv(version, &dest, Vec::new())
+        .await?
+        .encode(&mut buf);
+    Tlv::End.encode(&mut buf);
+    quic_send.write_all(&buf).await?;
+
+    let mut decoder = tlv::Decoder::new();
+    let reply = time::timeout(handshake_timeout, read_tlv(&mut quic_recv, &mut decoder))
+        .await
+        .map_err(|_| anyhow!("timed out waiting for TCP Connect OK TLV"))??;
+    match reply {
+        Some(Tlv::TcpConnectOk) => {}
+        Some(Tlv::Error(e)) => bail!("concentrator failed to connect to {}: {}", dest, e),
+        Some(t) => bail!("expected TCP Connect OK TLV received {:?}", t),
+        None => bail!("quic stream finished"),
+    }
+    debug!("connected to {} through {}", dest, pick.concentrator.addr);
+
+    let upload = async {
+        let mut stdin = io::stdin();
+        let mut buf = [0; STDIO_BUF_SIZE];
+        loop {
+            match stdin.read(&mut buf).await? {
+                0 => break,
+                n => quic_send.write_all(&buf[..n]).await?,
+            }
+        }
+        // half-close, keep reading until the concentrator finishes the stream
+        quic_send.finish().await?;
+        future::pending::<Result<()>>().await
+    };
+
+    let download = async {
+        let mut stdout = io::stdout();
+        // data sent by the tcp server may arrive together with the TLV
+        stdout.write_all(&decoder.into_payload()).await?;
+        stdout.flush().await?;
+
+        let mut buf = [0; STDIO_BUF_SIZE];
+        while let Some(n) = quic_recv.read(&mut buf).await? {
+            stdout.write_all(&buf[..n]).await?;
+            stdout.flush().await?;
+        }
+        stdout.flush().await?;
+        Ok(())
+    };
+
+    // don't wait for stdin once the destination closed the connection,
+    // e.g. ssh only closes its end after reading the end of stdout
+    tokio::select! {
+        res = upload => res,
+        res = download => res,
+    }
+}
+
+/// Pick a quic connection and open a stream on it.
+/// Waits while no concentrator is reachable, up to `RECONNECT_WAIT`.
+async fn open_stream(
+    balancer: &mut Balancer,
+    timeout: Duration,
+) -> Result<(Pick, SendStream, RecvStream)> {
+    let pick = balancer
+        .wait(RECONNECT_WAIT)
+        .await
+        .ok_or_else(|| anyhow!("no quic connection is up"))?;
+
+    trace!("opening quic stream to {}", pick.concentrator.addr);
+    let (quic_send, quic_recv) = time::timeout(timeout, pick.tunnel.connection.open_bi())
+        .await
+        .map_err(|_| {
+            anyhow!(
+                "timed out opening quic stream to {}",
+                pick.concentrator.addr
+            )
+        })??;
+    Ok((pick, quic_send, quic_recv))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::balancer::Strategy;
+
+    #[test]
+    fn parse_forward_test() {
+        assert_eq!(
+            vec![(6970, Destination::Host("db.internal".to_string(), 5432))],
+            parse_forward("6970=db.internal:5432").unwrap()
+        );
+        assert_eq!(
+            vec![
+                (7000, Destination::Addr("[fd00::1]:8000".parse().unwrap())),
+                (7001, Destination::Addr("[fd00::1]:8001".parse().unwrap())),
+                (7002, Destination::Addr("[fd00::1]:8002".parse().unwrap())),
+            ],
+            parse_forward("7000-7002=[fd00::1]:8000-8002").unwrap()
+        );
+
+        assert!(parse_forward("6970").is_err());
+        assert!(parse_forward("7000-7002=10.0.0.1:8000-8001").is_err());
+        assert!(parse_forward("7002-7000=10.0.0.1:8002-8000").is_err());
+        assert!(parse_forward("6970=10.0.0.1").is_err());
+    }
+
+    #[tokio::test]
+    async fn remove_rule_test() {
+        let mut forwards =
+            ForwardSet::new(Balancer::new(Strategy::RoundRobin), Duration::from_secs(1));
+        let dest: Destination = "127.0.0.1:7970".parse().unwrap();
+
+        // rules are removed by their bound address, even before their task runs
+        let addr = forwards
+            .add(Rule {
+                listen_addr: "127.0.0.1:0".parse().unwrap(),
+                frontend: Frontend::Forward(dest.clone()),
+            })
+            .await
+            .unwrap();
+        assert_ne!(0, addr.port());
+        let udp_addr = forwards
+            .add_udp("127.0.0.1:0".parse().unwrap(), dest)
+            .await
+            .unwrap();
+
+        let removed = time::timeout(Duration::from_secs(5), async {
+            (
+                forwards.remove(addr).await,
+                forwards.remove_udp(udp_addr).await,
+            )
+        });
+        assert_eq!((true, true), removed.await.unwrap());
+        assert!(!forwards.remove(addr).await);
+        time::timeout(Duration::from_secs(5), forwards.shutdown())
+            .await
+            .unwrap();
+    }
+}
@@ -1,14 +1,61 @@
+use crate::acl::Acl;
+use crate::auth::{self, TokenFile};
+use crate::identity::{self, IdentitySource};
+use crate::quic_tunnel::admission::Admission;
 use crate::quic_tunnel::connection;
+use crate::quic_tunnel::ratelimit::{ClientLimits, RateLimits};
+use crate::quic_tunnel::reverse::{self, Reverse};
+use crate::quic_tunnel::udp::Flows;
+use crate::quic_tunnel::version::{self, Version};
 use crate::Shutdown;
 use anyhow::{anyhow, Result};
+use futures::stream::SelectAll;
 use futures::StreamExt;
-use tokio::sync::{broadcast, mpsc};
-use tracing::{debug, error, info, trace};
+use quinn::VarInt;
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::sync::{broadcast, mpsc, Semaphore};
+use tokio::time::{self, Duration};
+use tracing::{debug, error, info, info_span, trace};
+use tracing_futures::Instrument;
 
 /// TCP Server listener state.
 /// which performs the TCP listening and initialization of per-connection state.
 pub struct Listener {
-    pub incoming: quinn::Incoming,
+    /// Connections of the quic endpoints of every bind address
+    pub incoming: SelectAll<quinn::Incoming>,
+
+    /// Time allowed to complete the quic handshake,
+    /// and then to receive the client's TLVs on each stream.
+    pub handshake_timeout: Duration,
+
+    /// Permits for quic handshakes in progress,
+    /// connections are refused when none is left.
+    pub pending_handshakes: Arc<Semaphore>,
+
+    /// Ports clients may listen on to expose their services,
+    /// `None` if reverse tunnels are disabled.
+    pub reverse: Option<Arc<reverse::Config>>,
+
+    /// Time after which UDP flows without traffic are closed
+    pub udp_idle_timeout: Duration,
+
+    /// Destinations clients may connect to,
+    /// per identity when clients authenticate with a certificate.
+    pub acl: Arc<Acl>,
+
+    /// Part of the client certificates their identity is taken from
+    pub identity_source: IdentitySource,
+
+    /// Tokens clients must authenticate with,
+    /// `None` if they don't need to.
+    pub tokens: Option<Arc<TokenFile>>,
+
+    /// Bandwidth of the clients' streams
+    pub rate_limits: Arc<RateLimits>,
+
+    /// Caps on the clients' connections, streams, dials and sockets
+    pub admission: Arc<Admission>,
 
     /// Broadcasts a shutdown signal to all active connections.
     pub notify_shutdown: broadcast::Sender<()>,
@@ -20,47 +67,179 @@ pub struct Listener {
 }
 
 struct ConnectionHandler {
-    _connection: quinn::Connection,
+    connection: quinn::Connection,
+    /// Tunnel protocol version negotiated with the client
+    version: Version,
+    /// Name of the client's token as `token:name`, else identity of its
+    /// certificate as `cert:identity`, `None` without client authentication
+    identity: Option<String>,
+    /// Expiry of the client's token, the connection is closed once reached
+    expires: Option<SystemTime>,
+    handshake_timeout: Duration,
+    reverse: Option<Arc<reverse::Config>>,
+    /// ACL of the client's identity
+    acl: Arc<Acl>,
+    /// Rate limits of the client, shared with its other connections
+    limits: ClientLimits,
+    admission: Arc<Admission>,
     bi_streams: quinn::IncomingBiStreams,
+    datagrams: quinn::Datagrams,
+    /// UDP flows opened by the client, their datagrams are dispatched by the handler
+    flows: Flows,
     shutdown: Shutdown,
     _shutdown_complete: mpsc::Sender<()>,
 }
 
 impl Listener {
-    /// Run the server
+    /// Run the server until the endpoint is closed
     pub async fn run(&mut self) -> Result<()> {
         info!("accepting inbound quic connections");
 
-        loop {
-            let conn = self.incoming.next().await;
+        while let Some(connecting) = self.incoming.next().await {
+            let remote = connecting.remote_address();
 
-            if conn.is_none() {
-                error!("empty connection received");
-                continue;
-            }
+            let permit = match self.pending_handshakes.clone().try_acquire_owned() {
+                Ok(permit) => permit,
+                Err(_) => {
+                    // dropping `connecting` closes the connection
+                    error!("too many pending handshakes, refusing {}", remote);
+                    continue;
+                }
+            };
+            let connection_permit = match self.admission.connection() {
+                Some(permit) => permit,
+                None => {
+                    error!("too many connections, refusing {}", remote);
+                    continue;
+                }
+            };
 
-            let quinn::NewConnection {
-                connection,
-                bi_streams,
-                ..
-            } = conn.unwrap().await?;
+            let handshake_timeout = self.handshake_timeout;
+            let reverse = self.reverse.clone();
+            let udp_idle_timeout = self.udp_idle_timeout;
+            let base_acl = self.acl.clone();
+            let identity_source = self.identity_source;
+            let tokens = self.tokens.clone();
+            let rate_limits = self.rate_limits.clone();
+            let admission = self.admission.clone();
+            let shutdown = Shutdown::new(self.notify_shutdown.subscribe());
+            let shutdown_complete = self.shutdown_complete_tx.clone();
+
+            // Spawn a new task to complete the handshake and process the connection,
+            // so a slow handshake doesn't hold the others.
+            tokio::spawn(async move {
+                let new_conn = match time::timeout(handshake_timeout, connecting).await {
+                    Ok(Ok(new_conn)) => new_conn,
+                    Ok(Err(e)) => {
+                        error!("quic handshake with {} failed {}", remote, e);
+                        return;
+                    }
+                    Err(_) => {
+                        error!("quic handshake with {} timed out", remote);
+                        return;
+                    }
+                };
+                drop(permit);
 
-            trace!("connection established {}", connection.remote_address());
+                let quinn::NewConnection {
+                    connection,
+                    mut bi_streams,
+                    datagrams,
+                    ..
+                } = new_conn;
 
-            let mut conn = ConnectionHandler {
-                _connection: connection,
-                bi_streams,
-                shutdown: Shutdown::new(self.notify_shutdown.subscribe()),
-                _shutdown_complete: self.shutdown_complete_tx.clone(),
-            };
+                trace!("connection established {}", remote);
 
-            // Spawn a new task to process the connections.
-            tokio::spawn(async move {
-                if let Err(err) = conn.run().await {
-                    error!(cause = ? err, "connection error");
+                let version = match version::negotiated(&connection) {
+                    Some(v) => v,
+                    None => {
+                        error!(
+                            "no tunnel version agreed with {}, closing connection",
+                            remote
+                        );
+                        connection.close(VarInt::from_u32(0), b"unsupported tunnel version");
+                        return;
+                    }
+                };
+                debug!("negotiated tunnel version {} with {}", version, remote);
+
+                // `--identity_allow` rules only apply to certificate identities,
+                // token and certificate identities are namespaced so they can't collide
+                let peer_identity = identity::peer_identity(&connection, identity_source);
+                let mut identity = peer_identity.as_ref().map(|id| format!("cert:{}", id));
+                let mut acl = base_acl.for_identity(peer_identity.as_deref());
+                let mut expires = None;
+                if let Some(tokens) = tokens {
+                    let entry = if version.supports_auth() {
+                        tokens.accept(&mut bi_streams, handshake_timeout).await
+                    } else {
+                        Err(anyhow!("{} doesn't support authentication", version))
+                    };
+                    match entry {
+                        Ok(entry) => {
+                            identity = Some(format!("token:{}", entry.name));
+                            expires = entry.expires;
+                            acl = if entry.allow.is_empty() {
+                                base_acl
+                            } else {
+                                base_acl.with_allow(entry.allow)
+                            };
+                        }
+                        Err(e) => {
+                            // e.g. clients shutting down before their first stream
+                            if let Some(quinn::ConnectionError::ApplicationClosed { .. }) =
+                                e.downcast_ref()
+                            {
+                                debug!("{} closed the connection before authenticating", remote);
+                            } else {
+                                error!("{} failed to authenticate {}", remote, e);
+                            }
+                            connection.close(auth::CLOSE_UNAUTHENTICATED, b"authentication failed");
+                            return;
+                        }
+                    }
+                }
+                if let Some(identity) = &identity {
+                    info!("{} authenticated as {}", remote, identity);
+                }
+                let span = info_span!(
+                    "connection",
+                    %remote,
+                    identity = identity.as_deref().unwrap_or("-")
+                );
+
+                let mut conn = ConnectionHandler {
+                    flows: Flows::new(connection.clone(), udp_idle_timeout),
+                    acl,
+                    limits: match &identity {
+                        Some(identity) => rate_limits.client(identity),
+                        None => rate_limits.client(&remote.ip().to_string()),
+                    },
+                    admission,
+                    connection,
+                    version,
+                    identity,
+                    expires,
+                    handshake_timeout,
+                    reverse,
+                    bi_streams,
+                    datagrams,
+                    shutdown,
+                    _shutdown_complete: shutdown_complete,
+                };
+                async move {
+                    if let Err(err) = conn.run().await {
+                        error!(cause = ? err, "connection error with {}", remote);
+                    }
+                    drop(connection_permit);
                 }
+                .instrument(span)
+                .await
             });
         }
+
+        info!("quic endpoints closed");
+        Ok(())
     }
 }
 
@@ -71,20 +250,33 @@ impl ConnectionHandler {
         let (notify_shutdown, _) = broadcast::channel(1);
         let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
 
+        let remaining = self.expires.map(|expires| {
+            expires
+                .duration_since(SystemTime::now())
+                .unwrap_or_default()
+        });
+        let mut expired = time::delay_for(remaining.unwrap_or_default());
+
         while !self.shutdown.is_shutdown() {
             tokio::select! {
                 stream = self.bi_streams.next() => {
 
                     if stream.is_none() {
-                        debug!("none received from stream!!");
-                        continue;
+                        debug!("quic connection closed locally exiting...");
+                        drop(notify_shutdown);
+                        drop(shutdown_complete_tx);
+                        let _ = shutdown_complete_rx.recv().await;
+                        return Ok(());
                     }
 
                     debug!("stream established");
 
                     let stream = match stream.unwrap() {
                         Err(quinn::ConnectionError::ApplicationClosed { .. }) => {
-                            debug!("incoming quic connection closed exiting...");
+                            debug!(
+                                "incoming quic connection of {} closed exiting...",
+                                self.identity.as_deref().unwrap_or("anonymous client")
+                            );
                             drop(notify_shutdown);
                             drop(shutdown_complete_tx);
                             let _ = shutdown_complete_rx.recv().await;
@@ -99,9 +291,24 @@ impl ConnectionHandler {
                         }
                         Ok(s) => s,
                     };
-                    let ( send,  recv) = stream;
+                    let (send, recv) = stream;
 
                     let mut conn = connection::Connection{
+                        version: self.version,
+                        handshake_timeout: self.handshake_timeout,
+                        reverse: self.reverse.clone().map(|config| Reverse {
+                            connection: self.connection.clone(),
+                            config,
+                        }),
+                        reverse_dests: None,
+                        udp: if self.version.supports_udp() {
+                            Some(self.flows.clone())
+                        } else {
+                            None
+                        },
+                        acl: Some(self.acl.clone()),
+                        limits: self.limits.clone(),
+                        admission: Some(self.admission.clone()),
                         shutdown: Shutdown::new(notify_shutdown.subscribe()),
                         _shutdown_complete: shutdown_complete_tx.clone(),
                     };
@@ -110,7 +317,18 @@ impl ConnectionHandler {
                         if let Err(err) = conn.run_concentrator_conn(send,recv).await {
                             error!(cause = ? err, "stream connection error");
                         }
-                    });
+                    }.in_current_span());
+                }
+
+                Some(Ok(datagram)) = self.datagrams.next() => self.flows.dispatch(datagram),
+
+                _ = &mut expired, if remaining.is_some() => {
+                    info!("token of {} expired, closing connection", self.identity.as_deref().unwrap_or("-"));
+                    self.connection.close(auth::CLOSE_UNAUTHENTICATED, b"token expired");
+                    drop(notify_shutdown);
+                    drop(shutdown_complete_tx);
+                    let _ = shutdown_complete_rx.recv().await;
+                    return Ok(());
                 }
 
                 // wait for shutdown signal
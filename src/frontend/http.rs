@@ -0,0 +1,180 @@
+//! HTTP/1.1 CONNECT proxy, RFC 7231 section 4.3.6.
+use crate::quic_tunnel::destination::Destination;
+use crate::quic_tunnel::tlv::TlvError;
+use anyhow::{anyhow, bail, Result};
+use bytes::BytesMut;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::prelude::*;
+
+/// Requests with larger headers are refused.
+const MAX_HEADER_SIZE: usize = 8192;
+
+/// Read the CONNECT request and returns the requested destination,
+/// along with any bytes the client sent after the request.
+/// Invalid requests are answered with an error response before returning an error.
+pub async fn accept<S>(stream: &mut S) -> Result<(Destination, BytesMut)>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut buf = BytesMut::with_capacity(1024);
+    let header_len = loop {
+        if let Some(pos) = buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            break pos + 4;
+        }
+        if buf.len() >= MAX_HEADER_SIZE {
+            respond(stream, "431 Request Header Fields Too Large", "").await?;
+            bail!("http request header too large");
+        }
+        if stream.read_buf(&mut buf).await? == 0 {
+            bail!("connection closed before end of http request");
+        }
+    };
+    let header = buf.split_to(header_len);
+
+    match parse_request(&header) {
+        Ok(dest) => Ok((dest, buf)),
+        Err(RequestError::MethodNotAllowed(method)) => {
+            respond(
+                stream,
+                "405 Method Not Allowed",
+                "only CONNECT is supported\n",
+            )
+            .await?;
+            bail!("unsupported http method {}", method)
+        }
+        Err(RequestError::BadRequest(e)) => {
+            respond(stream, "400 Bad Request", &format!("{}\n", e)).await?;
+            Err(e)
+        }
+    }
+}
+
+/// Tell the client the tunnel is established.
+pub async fn connected<S>(stream: &mut S) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream
+        .write_all(b"HTTP/1.1 200 Connection Established\r\n\r\n")
+        .await?;
+    Ok(())
+}
+
+/// Tell the client the concentrator couldn't connect to the destination.
+/// Every error is a 502, the body carries the concentrator's error.
+pub async fn refuse<S>(stream: &mut S, err: &TlvError) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    respond(stream, "502 Bad Gateway", &format!("{}\n", err)).await
+}
+
+enum RequestError {
+    MethodNotAllowed(String),
+    BadRequest(anyhow::Error),
+}
+
+fn parse_request(header: &[u8]) -> std::result::Result<Destination, RequestError> {
+    let header = std::str::from_utf8(header)
+        .map_err(|_| RequestError::BadRequest(anyhow!("http request is not UTF-8")))?;
+    let request_line = header.lines().next().unwrap_or_default();
+
+    let mut parts = request_line.split_whitespace();
+    let (method, target, version) = match (parts.next(), parts.next(), parts.next()) {
+        (Some(method), Some(target), Some(version)) if parts.next().is_none() => {
+            (method, target, version)
+        }
+        _ => {
+            return Err(RequestError::BadRequest(anyhow!(
+                "invalid http request line {:?}",
+                request_line
+            )))
+        }
+    };
+
+    if !version.starts_with("HTTP/1.") {
+        return Err(RequestError::BadRequest(anyhow!(
+            "unsupported http version {}",
+            version
+        )));
+    }
+    if method != "CONNECT" {
+        return Err(RequestError::MethodNotAllowed(method.to_string()));
+    }
+
+    // authority-form, host:port
+    target.parse().map_err(RequestError::BadRequest)
+}
+
+async fn respond<S>(stream: &mut S, status: &str, body: &str) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::quic_tunnel::tlv;
+    use futures::FutureExt;
+
+    async fn negotiate(request: &'static [u8]) -> (Result<(Destination, BytesMut)>, Vec<u8>) {
+        crate::frontend::tests::negotiate(request, |stream| accept(stream).boxed()).await
+    }
+
+    #[tokio::test]
+    async fn accept_connect_test() {
+        let (res, response) =
+            negotiate(b"CONNECT db.internal:5432 HTTP/1.1\r\nHost: db.internal:5432\r\n\r\nhello")
+                .await;
+        let (dest, payload) = res.unwrap();
+
+        assert_eq!(Destination::Host("db.internal".to_string(), 5432), dest);
+        assert_eq!(&b"hello"[..], &payload[..]);
+        assert!(response.is_empty());
+    }
+
+    #[tokio::test]
+    async fn accept_ipv6_test() {
+        let (res, _) = negotiate(b"CONNECT [fd00::1]:443 HTTP/1.1\r\n\r\n").await;
+        assert_eq!(
+            Destination::Addr("[fd00::1]:443".parse().unwrap()),
+            res.unwrap().0
+        );
+    }
+
+    #[tokio::test]
+    async fn accept_invalid_test() {
+        let (res, response) = negotiate(b"GET http://example.com/ HTTP/1.1\r\n\r\n").await;
+        assert!(res.is_err());
+        assert!(response.starts_with(b"HTTP/1.1 405 "));
+
+        let (res, response) = negotiate(b"CONNECT example.com HTTP/1.1\r\n\r\n").await;
+        assert!(res.is_err());
+        assert!(response.starts_with(b"HTTP/1.1 400 "));
+    }
+
+    #[tokio::test]
+    async fn refuse_test() {
+        for code in &[
+            tlv::ERROR_TYPE_NETWORK_FAILURE,
+            tlv::ERROR_TYPE_ACCESS_DENIED,
+            tlv::ERROR_TYPE_RESOURCE_LIMIT,
+        ] {
+            let mut response = Vec::new();
+            refuse(&mut response, &TlvError::new(*code, "refused"))
+                .await
+                .unwrap();
+            assert!(response.starts_with(b"HTTP/1.1 502 "));
+            assert!(response.ends_with(b"refused\n"));
+        }
+    }
+}
@@ -0,0 +1,107 @@
+pub mod http;
+
+pub mod socks5;
+
+use crate::quic_tunnel::destination::Destination;
+use crate::quic_tunnel::sockopt;
+use crate::quic_tunnel::tlv::TlvError;
+use anyhow::Result;
+use bytes::BytesMut;
+use tokio::net::TcpStream;
+use tokio::time::Duration;
+
+/// How the client learns the destination of an accepted tcp connection,
+/// and reports the outcome of the TCP Connect back to the tcp client.
+#[derive(Debug, Clone)]
+pub enum Frontend {
+    /// Every connection is forwarded to the same destination.
+    Forward(Destination),
+    /// The destination is requested with SOCKS5 CONNECT.
+    Socks5,
+    /// The destination is requested with HTTP CONNECT.
+    HttpConnect,
+    /// Connections are redirected to the client by iptables REDIRECT or TPROXY
+    /// and forwarded to their original destination.
+    Transparent,
+}
+
+impl Frontend {
+    /// Returns the destination requested by the tcp client,
+    /// and any data the tcp client sent past its request.
+    pub async fn accept(&self, tcp_streamer: &mut TcpStream) -> Result<(Destination, BytesMut)> {
+        match self {
+            Frontend::Forward(dest) => Ok((dest.clone(), BytesMut::new())),
+            Frontend::Socks5 => Ok((socks5::accept(tcp_streamer).await?, BytesMut::new())),
+            Frontend::HttpConnect => http::accept(tcp_streamer).await,
+            Frontend::Transparent => {
+                let dest = sockopt::original_dst(tcp_streamer)?;
+                Ok((dest.into(), BytesMut::new()))
+            }
+        }
+    }
+
+    /// Tell the tcp client the tunnel is established.
+    pub async fn connected(&self, tcp_streamer: &mut TcpStream) -> Result<()> {
+        match self {
+            Frontend::Forward(_) | Frontend::Transparent => Ok(()),
+            Frontend::Socks5 => socks5::reply(tcp_streamer, socks5::REP_SUCCEEDED).await,
+            Frontend::HttpConnect => http::connected(tcp_streamer).await,
+        }
+    }
+
+    /// Tell the tcp client the tunnel failed with `err` and close the connection.
+    pub async fn refuse(&self, mut tcp_streamer: TcpStream, err: &TlvError) -> Result<()> {
+        match self {
+            Frontend::Forward(_) | Frontend::Transparent => reset(tcp_streamer),
+            Frontend::Socks5 => {
+                socks5::reply(&mut tcp_streamer, socks5::reply_code(err)).await?;
+                tcp_streamer.shutdown(std::net::Shutdown::Both)?;
+                Ok(())
+            }
+            Frontend::HttpConnect => {
+                http::refuse(&mut tcp_streamer, err).await?;
+                tcp_streamer.shutdown(std::net::Shutdown::Both)?;
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Close the tcp connection with a RST instead of a FIN,
+/// so the tcp client sees the connection as refused.
+pub fn reset(tcp_streamer: TcpStream) -> Result<()> {
+    tcp_streamer.set_linger(Some(Duration::from_secs(0)))?;
+    drop(tcp_streamer);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::future::BoxFuture;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::prelude::*;
+
+    /// Send `request` from a local tcp client and run `accept` on the accepted connection.
+    /// Returns the result of `accept` and everything the tcp client received.
+    pub(crate) async fn negotiate<T, F>(request: &'static [u8], accept: F) -> (T, Vec<u8>)
+    where
+        F: for<'a> FnOnce(&'a mut TcpStream) -> BoxFuture<'a, T>,
+    {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let client = tokio::spawn(async move {
+            let mut stream = TcpStream::connect(addr).await.unwrap();
+            stream.write_all(request).await.unwrap();
+            let _ = stream.shutdown(std::net::Shutdown::Write);
+            let mut response = Vec::new();
+            stream.read_to_end(&mut response).await.unwrap();
+            response
+        });
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let res = accept(&mut stream).await;
+        drop(stream);
+        (res, client.await.unwrap())
+    }
+}
@@ -0,0 +1,174 @@
+//! SOCKS5 CONNECT negotiation, RFC 1928.
+use crate::quic_tunnel::destination::{is_valid_host, Destination};
+use crate::quic_tunnel::tlv::{self, TlvError};
+use anyhow::{bail, Result};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::prelude::*;
+
+const VERSION: u8 = 5;
+
+const METHOD_NO_AUTH: u8 = 0x00;
+const METHOD_NOT_ACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+pub const REP_SUCCEEDED: u8 = 0x00;
+pub const REP_GENERAL_FAILURE: u8 = 0x01;
+pub const REP_NOT_ALLOWED: u8 = 0x02;
+pub const REP_NETWORK_UNREACHABLE: u8 = 0x03;
+pub const REP_HOST_UNREACHABLE: u8 = 0x04;
+pub const REP_CONNECTION_REFUSED: u8 = 0x05;
+pub const REP_COMMAND_NOT_SUPPORTED: u8 = 0x07;
+pub const REP_ADDRESS_TYPE_NOT_SUPPORTED: u8 = 0x08;
+
+/// Perform the method selection and read the CONNECT request.
+/// Returns the requested destination, unsupported requests are answered
+/// with a failure reply before returning an error.
+pub async fn accept<S>(stream: &mut S) -> Result<Destination>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    // method selection
+    let mut header = [0; 2];
+    stream.read_exact(&mut header).await?;
+    if header[0] != VERSION {
+        bail!("unsupported socks version {}", header[0]);
+    }
+    let mut methods = vec![0; header[1] as usize];
+    stream.read_exact(&mut methods).await?;
+
+    if !methods.contains(&METHOD_NO_AUTH) {
+        stream.write_all(&[VERSION, METHOD_NOT_ACCEPTABLE]).await?;
+        bail!("socks client doesn't support no authentication method");
+    }
+    stream.write_all(&[VERSION, METHOD_NO_AUTH]).await?;
+
+    // request
+    let mut request = [0; 4];
+    stream.read_exact(&mut request).await?;
+    let [version, cmd, _, atyp] = request;
+    if version != VERSION {
+        bail!("unsupported socks version {}", version);
+    }
+    let dest = match atyp {
+        ATYP_IPV4 => {
+            let mut addr = [0; 4];
+            stream.read_exact(&mut addr).await?;
+            let port = stream.read_u16().await?;
+            Destination::Addr(SocketAddr::new(IpAddr::V4(Ipv4Addr::from(addr)), port))
+        }
+        ATYP_IPV6 => {
+            let mut addr = [0; 16];
+            stream.read_exact(&mut addr).await?;
+            let port = stream.read_u16().await?;
+            Destination::Addr(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(addr)), port))
+        }
+        ATYP_DOMAIN => {
+            let len = stream.read_u8().await?;
+            let mut host = vec![0; len as usize];
+            stream.read_exact(&mut host).await?;
+            let port = stream.read_u16().await?;
+
+            let host = String::from_utf8_lossy(&host).to_ascii_lowercase();
+            // some clients send IP literals as domain names
+            match host.parse::<IpAddr>() {
+                Ok(ip) => Destination::Addr(SocketAddr::new(ip, port)),
+                Err(_) if is_valid_host(&host) => Destination::Host(host, port),
+                Err(_) => {
+                    reply(stream, REP_GENERAL_FAILURE).await?;
+                    bail!("invalid socks domain name {}", host);
+                }
+            }
+        }
+        _ => {
+            reply(stream, REP_ADDRESS_TYPE_NOT_SUPPORTED).await?;
+            bail!("unsupported socks address type {}", atyp);
+        }
+    };
+
+    // the whole request is read before refusing it,
+    // so the reply isn't lost to a reset
+    if cmd != CMD_CONNECT {
+        reply(stream, REP_COMMAND_NOT_SUPPORTED).await?;
+        bail!("unsupported socks command {}", cmd);
+    }
+
+    Ok(dest)
+}
+
+/// Send the reply to the CONNECT request.
+/// The bound address is not known on this side of the tunnel and is sent as zero.
+pub async fn reply<S>(stream: &mut S, rep: u8) -> Result<()>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream
+        .write_all(&[VERSION, rep, 0, ATYP_IPV4, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Ok(())
+}
+
+/// Map the error returned by the concentrator to a reply code.
+pub fn reply_code(err: &TlvError) -> u8 {
+    match err.code {
+        tlv::ERROR_TYPE_ICMP_PKT_RECV => REP_HOST_UNREACHABLE,
+        tlv::ERROR_TYPE_NETWORK_FAILURE => REP_CONNECTION_REFUSED,
+        tlv::ERROR_TYPE_ACCESS_DENIED => REP_NOT_ALLOWED,
+        _ => REP_GENERAL_FAILURE,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    async fn negotiate(request: &'static [u8]) -> (Result<Destination>, Vec<u8>) {
+        crate::frontend::tests::negotiate(request, |stream| accept(stream).boxed()).await
+    }
+
+    #[tokio::test]
+    async fn accept_ipv4_test() {
+        let (dest, response) = negotiate(&[5, 1, 0, 5, 1, 0, 1, 10, 0, 0, 1, 0, 80]).await;
+        assert_eq!(
+            Destination::Addr("10.0.0.1:80".parse().unwrap()),
+            dest.unwrap()
+        );
+        assert_eq!(vec![5, 0], response);
+    }
+
+    #[tokio::test]
+    async fn accept_domain_test() {
+        let (dest, _) = negotiate(b"\x05\x01\x00\x05\x01\x00\x03\x0bdb.internal\x15\x38").await;
+        assert_eq!(
+            Destination::Host("db.internal".to_string(), 5432),
+            dest.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn accept_unsupported_test() {
+        // username/password authentication only
+        let (dest, response) = negotiate(&[5, 1, 2]).await;
+        assert!(dest.is_err());
+        assert_eq!(vec![5, METHOD_NOT_ACCEPTABLE], response);
+
+        // BIND command
+        let (dest, response) = negotiate(&[5, 1, 0, 5, 2, 0, 1, 10, 0, 0, 1, 0, 80]).await;
+        assert!(dest.is_err());
+        assert_eq!(REP_COMMAND_NOT_SUPPORTED, response[3]);
+    }
+
+    #[test]
+    fn reply_code_test() {
+        let denied = TlvError::new(tlv::ERROR_TYPE_ACCESS_DENIED, "");
+        assert_eq!(REP_NOT_ALLOWED, reply_code(&denied));
+        let refused = TlvError::new(tlv::ERROR_TYPE_NETWORK_FAILURE, "");
+        assert_eq!(REP_CONNECTION_REFUSED, reply_code(&refused));
+    }
+}
@@ -0,0 +1,195 @@
+use anyhow::{anyhow, bail, ensure, Result};
+use rustls::internal::pemfile;
+use rustls::{AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore, ServerConfig};
+use std::fmt;
+use std::fs;
+use std::str::FromStr;
+use x509_parser::certificate::X509Certificate;
+use x509_parser::extensions::GeneralName;
+use x509_parser::prelude::FromDer;
+
+/// Part of the client certificate its identity is taken from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IdentitySource {
+    /// The first DNS, URI or email subject alternative name, else the subject common name.
+    Any,
+    /// The first DNS subject alternative name.
+    Dns,
+    /// The first URI subject alternative name, e.g. a SPIFFE ID.
+    Uri,
+    /// The first email subject alternative name.
+    Email,
+    /// The subject common name.
+    CommonName,
+}
+
+impl FromStr for IdentitySource {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<IdentitySource> {
+        match s {
+            "any" => Ok(IdentitySource::Any),
+            "dns" => Ok(IdentitySource::Dns),
+            "uri" => Ok(IdentitySource::Uri),
+            "email" => Ok(IdentitySource::Email),
+            "cn" => Ok(IdentitySource::CommonName),
+            _ => bail!("unknown client identity source {}", s),
+        }
+    }
+}
+
+impl fmt::Display for IdentitySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentitySource::Any => write!(f, "any"),
+            IdentitySource::Dns => write!(f, "dns"),
+            IdentitySource::Uri => write!(f, "uri"),
+            IdentitySource::Email => write!(f, "email"),
+            IdentitySource::CommonName => write!(f, "cn"),
+        }
+    }
+}
+
+/// Read the certificates of a PEM or DER file.
+pub fn load_certs(path: &str) -> Result<Vec<Certificate>> {
+    let data = fs::read(path).map_err(|e| anyhow!("unable to read {} {}", path, e))?;
+    if !data.is_ascii() {
+        return Ok(vec![Certificate(data)]);
+    }
+    let certs = pemfile::certs(&mut &data[..])
+        .map_err(|_| anyhow!("unable to parse certificates of {}", path))?;
+    ensure!(!certs.is_empty(), "no certificate in {}", path);
+    Ok(certs)
+}
+
+/// Read a PKCS#8 or RSA private key of a PEM or DER file.
+pub fn load_key(path: &str) -> Result<PrivateKey> {
+    let data = fs::read(path).map_err(|e| anyhow!("unable to read {} {}", path, e))?;
+    if !data.is_ascii() {
+        return Ok(PrivateKey(data));
+    }
+    let mut keys = pemfile::pkcs8_private_keys(&mut &data[..]).unwrap_or_default();
+    if keys.is_empty() {
+        keys = pemfile::rsa_private_keys(&mut &data[..]).unwrap_or_default();
+    }
+    keys.pop()
+        .ok_or_else(|| anyhow!("no private key in {}", path))
+}
+
+/// Require clients to present a certificate signed by one of the CAs of `ca_path`.
+pub fn require_client_certs(config: &mut ServerConfig, ca_path: &str) -> Result<()> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(ca_path)? {
+        roots
+            .add(&cert)
+            .map_err(|e| anyhow!("invalid client CA in {} {:?}", ca_path, e))?;
+    }
+    config.set_client_certificate_verifier(AllowAnyAuthenticatedClient::new(roots));
+    Ok(())
+}
+
+/// Returns the identity of the verified certificate the peer presented,
+/// `None` if it didn't present one.
+pub fn peer_identity(connection: &quinn::Connection, source: IdentitySource) -> Option<String> {
+    let certs = connection.authentication_data().peer_certificates?;
+    let cert = certs.iter().next()?;
+    identity(&cert.0, source)
+}
+
+/// Returns the identity of a DER certificate taken from `source`,
+/// `None` if the certificate is invalid or has no such name.
+pub fn identity(der: &[u8], source: IdentitySource) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    let alt_names = cert
+        .subject_alternative_name()
+        .ok()
+        .flatten()
+        .map(|ext| ext.value.general_names.clone())
+        .unwrap_or_default();
+    let alt_name = alt_names.iter().find_map(|name| match (source, name) {
+        (IdentitySource::Any, GeneralName::DNSName(name))
+        | (IdentitySource::Any, GeneralName::URI(name))
+        | (IdentitySource::Any, GeneralName::RFC822Name(name))
+        | (IdentitySource::Dns, GeneralName::DNSName(name))
+        | (IdentitySource::Uri, GeneralName::URI(name))
+        | (IdentitySource::Email, GeneralName::RFC822Name(name)) => Some(name.to_string()),
+        _ => None,
+    });
+    let common_name = || {
+        let cn = cert.subject().iter_common_name().next()?;
+        cn.as_str().ok().map(str::to_string)
+    };
+    match source {
+        IdentitySource::Any => alt_name.or_else(common_name),
+        IdentitySource::CommonName => common_name(),
+        _ => alt_name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rcgen::{CertificateParams, CustomExtension, DistinguishedName, DnType};
+
+    #[test]
+    fn identity_test() {
+        let cert = rcgen::generate_simple_self_signed(vec!["alice.clients".into()]).unwrap();
+        let der = cert.serialize_der().unwrap();
+        assert_eq!(
+            Some("alice.clients".to_string()),
+            identity(&der, IdentitySource::Any)
+        );
+
+        let mut params = CertificateParams::default();
+        params.subject_alt_names = Vec::new();
+        params.distinguished_name = DistinguishedName::new();
+        params
+            .distinguished_name
+            .push(DnType::OrganizationName, "example");
+        params.distinguished_name.push(DnType::CommonName, "bob");
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let der = cert.serialize_der().unwrap();
+        assert_eq!(Some("bob".to_string()), identity(&der, IdentitySource::Any));
+        assert_eq!(None, identity(&der, IdentitySource::Dns));
+
+        assert_eq!(None, identity(&der[..der.len() / 2], IdentitySource::Any));
+        assert_eq!(None, identity(b"", IdentitySource::Any));
+    }
+
+    #[test]
+    fn identity_source_test() {
+        // rcgen doesn't write URI names, the extension is encoded here
+        let names: &[(u8, &str)] = &[
+            (0x82, "carol.clients"),
+            (0x86, "spiffe://example.org/carol"),
+            (0x81, "carol@example.org"),
+        ];
+        let mut san = Vec::new();
+        for (tag, name) in names {
+            san.extend_from_slice(&[*tag, name.len() as u8]);
+            san.extend_from_slice(name.as_bytes());
+        }
+        san.splice(0..0, vec![0x30, san.len() as u8]);
+
+        let mut params = CertificateParams::default();
+        params.subject_alt_names = Vec::new();
+        params.custom_extensions = vec![CustomExtension::from_oid_content(&[2, 5, 29, 17], san)];
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(DnType::CommonName, "carol");
+        let cert = rcgen::Certificate::from_params(params).unwrap();
+        let der = cert.serialize_der().unwrap();
+
+        let expected = [
+            ("any", "carol.clients"),
+            ("dns", "carol.clients"),
+            ("uri", "spiffe://example.org/carol"),
+            ("email", "carol@example.org"),
+            ("cn", "carol"),
+        ];
+        for (source, name) in &expected {
+            let source: IdentitySource = source.parse().unwrap();
+            assert_eq!(Some(name.to_string()), identity(&der, source), "{}", source);
+        }
+        assert!("san".parse::<IdentitySource>().is_err());
+    }
+}
@@ -1,8 +1,22 @@
+pub mod acl;
+
+pub mod auth;
+
+pub mod balancer;
+
 pub mod concentrator;
 
 pub mod client;
 
+pub mod frontend;
+
+pub mod identity;
+
+pub mod pool;
+
 pub mod quic_tunnel;
 
 pub mod shutdown;
+
+pub mod supervisor;
 use shutdown::Shutdown;
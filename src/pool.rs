@@ -0,0 +1,225 @@
+use crate::auth::Credentials;
+use crate::quic_tunnel::reverse::Registration;
+use crate::supervisor::{Demand, Supervisor, Tunnel, TunnelWatch};
+use anyhow::{anyhow, ensure, Result};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tokio::time::Duration;
+use tracing::debug;
+
+/// Streams opened on a connection before another one of the pool is used,
+/// below the stream window of the concentrator.
+pub const DEFAULT_STREAM_CAP: usize = 64;
+
+/// Default `--max_streams_per_connection` of the concentrator, its stream window.
+/// Past it `open_bi` waits for a stream to end instead of another connection
+/// of the pool being used, so the stream cap has to stay below it.
+pub const CONCENTRATOR_STREAM_WINDOW: usize = 256;
+
+#[derive(Clone)]
+struct Slot {
+    tunnel: TunnelWatch,
+    /// Active streams opened on the slot's connection
+    streams: Arc<AtomicUsize>,
+    /// Starts the slot's supervisor, `None` for the first slot which is always connected
+    demand: Option<Demand>,
+}
+
+/// Quic connections to one concentrator.
+/// The first one is always open, the others are opened on demand once
+/// every open connection carries `stream_cap` streams, and closed once idle.
+#[derive(Clone)]
+pub struct Pool {
+    slots: Vec<Slot>,
+    stream_cap: usize,
+    /// Notified when a stream ends, so streams waiting for a full pool can retry
+    released: Arc<Notify>,
+}
+
+/// Connection of the pool chosen for a new stream.
+pub struct PoolPick {
+    pub tunnel: Tunnel,
+    /// Active streams of the connection, the caller decrements it when the stream ends
+    pub streams: Arc<AtomicUsize>,
+    /// The caller notifies it when the stream ends
+    pub released: Arc<Notify>,
+}
+
+impl Pool {
+    /// Returns the pool and the supervisors keeping its `size` connections open.
+    /// `reverse` tunnels are registered on the first connection.
+    pub fn new(
+        endpoint: &quinn::Endpoint,
+        server_addr: SocketAddr,
+        server_name: &str,
+        size: usize,
+        stream_cap: usize,
+        reverse: Option<Registration>,
+        credentials: Option<Credentials>,
+    ) -> (Pool, Vec<Supervisor>) {
+        let mut slots = Vec::with_capacity(size);
+        let mut supervisors = Vec::with_capacity(size);
+        for i in 0..size.max(1) {
+            let streams = Arc::new(AtomicUsize::new(0));
+            let demand = if i == 0 {
+                None
+            } else {
+                Some(Demand::new(streams.clone()))
+            };
+            let (supervisor, tunnel) = Supervisor::new(
+                endpoint.clone(),
+                server_addr,
+                server_name,
+                demand.clone(),
+                if i == 0 { reverse.clone() } else { None },
+                credentials.clone(),
+            );
+            supervisors.push(supervisor);
+            slots.push(Slot {
+                tunnel,
+                streams,
+                demand,
+            });
+        }
+        let pool = Pool {
+            slots,
+            stream_cap,
+            released: Arc::new(Notify::new()),
+        };
+        (pool, supervisors)
+    }
+
+    /// `true` if any connection of the pool is open.
+    pub fn is_healthy(&self) -> bool {
+        self.slots.iter().any(|s| s.tunnel.borrow().is_some())
+    }
+
+    /// Active streams on all connections of the pool.
+    pub fn streams(&self) -> usize {
+        self.slots
+            .iter()
+            .map(|s| s.streams.load(Ordering::Relaxed))
+            .sum()
+    }
+
+    /// Lowest handshake latency of the open connections.
+    pub fn latency(&self) -> Option<Duration> {
+        self.slots
+            .iter()
+            .filter_map(|s| s.tunnel.borrow().as_ref().map(|t| t.latency))
+            .min()
+    }
+
+    /// Pick the open connection with the fewest streams. Returns `None` if
+    /// they all reached the stream cap, opening another connection if any is left.
+    /// The stream is counted on the picked connection.
+    pub fn pick(&self) -> Option<PoolPick> {
+        let open: Vec<(&Slot, Tunnel, usize)> = self
+            .slots
+            .iter()
+            .filter_map(|s| {
+                let tunnel = s.tunnel.borrow().clone()?;
+                Some((s, tunnel, s.streams.load(Ordering::Relaxed)))
+            })
+            .collect();
+
+        // while the concentrator is down only the first connection reconnects
+        let (slot, tunnel, streams) = open.into_iter().min_by_key(|(_, _, streams)| *streams)?;
+        if streams >= self.stream_cap {
+            let connecting = self.slots.iter().any(|s| {
+                s.tunnel.borrow().is_none() && s.demand.as_ref().is_some_and(Demand::is_started)
+            });
+            if !connecting
+                && self
+                    .slots
+                    .iter()
+                    .filter_map(|s| s.demand.as_ref())
+                    .any(Demand::start)
+            {
+                debug!("opening another quic connection to the concentrator");
+            }
+            return None;
+        }
+
+        slot.streams.fetch_add(1, Ordering::Relaxed);
+        Some(PoolPick {
+            tunnel,
+            streams: slot.streams.clone(),
+            released: self.released.clone(),
+        })
+    }
+
+    /// Notified when a stream of the pool ends.
+    pub fn released(&self) -> Arc<Notify> {
+        self.released.clone()
+    }
+
+    /// Pool of already supervised tunnels, the first one always connected.
+    #[cfg(test)]
+    pub(crate) fn with_tunnels(tunnels: Vec<TunnelWatch>, stream_cap: usize) -> Pool {
+        let slots = tunnels
+            .into_iter()
+            .enumerate()
+            .map(|(i, tunnel)| {
+                let streams = Arc::new(AtomicUsize::new(0));
+                Slot {
+                    tunnel,
+                    demand: if i == 0 {
+                        None
+                    } else {
+                        Some(Demand::new(streams.clone()))
+                    },
+                    streams,
+                }
+            })
+            .collect();
+        Pool {
+            slots,
+            stream_cap,
+            released: Arc::new(Notify::new()),
+        }
+    }
+
+    /// Tunnels of the pool, to wait for changes.
+    pub fn tunnels(&mut self) -> impl Iterator<Item = &mut TunnelWatch> {
+        self.slots.iter_mut().map(|s| &mut s.tunnel)
+    }
+}
+
+/// Parses a stream cap, refusing 0 which would leave no stream to any connection.
+pub fn parse_stream_cap(s: &str) -> Result<usize> {
+    let cap = s.parse().map_err(|_| anyhow!("invalid stream cap {}", s))?;
+    ensure!(cap > 0, "stream cap must be positive");
+    Ok(cap)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::watch;
+
+    #[test]
+    fn pick_down_test() {
+        let (_senders, tunnels): (Vec<_>, Vec<_>) = (0..3).map(|_| watch::channel(None)).unzip();
+        let pool = Pool::with_tunnels(tunnels, DEFAULT_STREAM_CAP);
+
+        // nothing is open, extra connections aren't started
+        assert!(pool.pick().is_none());
+        assert!(!pool.slots[1].demand.as_ref().unwrap().is_started());
+        assert!(!pool.slots[2].demand.as_ref().unwrap().is_started());
+
+        assert!(!pool.is_healthy());
+        assert_eq!(0, pool.streams());
+        assert_eq!(None, pool.latency());
+    }
+
+    #[test]
+    fn parse_stream_cap_test() {
+        assert_eq!(64, parse_stream_cap("64").unwrap());
+        assert!(parse_stream_cap("0").is_err());
+        assert!(parse_stream_cap("-1").is_err());
+        assert!(parse_stream_cap("many").is_err());
+    }
+}
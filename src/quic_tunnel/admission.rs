@@ -0,0 +1,190 @@
+use crate::quic_tunnel::destination::Destination;
+use crate::quic_tunnel::tlv::{self, TlvError};
+use anyhow::{anyhow, ensure, Result};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// Most permits a tokio semaphore can hold, it panics above.
+pub const MAX_LIMIT: usize = usize::MAX >> 3;
+
+/// Caps on the resources the concentrator's clients may use.
+#[derive(Debug, Clone, Copy)]
+pub struct Limits {
+    /// QUIC connections of all the clients
+    pub connections: usize,
+    /// Streams open at once on each connection, the QUIC stream window
+    /// which holds back clients opening more
+    pub streams_per_connection: usize,
+    /// Outbound connections in progress
+    pub pending_dials: usize,
+    /// Outbound connections in progress to each destination
+    pub pending_dials_per_dest: usize,
+    /// Sockets open towards destinations, including reverse tunnel listeners
+    /// and the connections they accept
+    pub backend_sockets: usize,
+}
+
+/// Admission control of the concentrator, refusing what exceeds its limits
+/// instead of running out of file descriptors.
+#[derive(Debug)]
+pub struct Admission {
+    limits: Limits,
+    connections: Arc<Semaphore>,
+    dials: Arc<Semaphore>,
+    /// Dials in progress per destination
+    dests: Arc<Mutex<HashMap<Destination, usize>>>,
+    sockets: Arc<Semaphore>,
+}
+
+impl Admission {
+    pub fn new(limits: Limits) -> Admission {
+        Admission {
+            limits,
+            connections: Arc::new(Semaphore::new(limits.connections)),
+            dials: Arc::new(Semaphore::new(limits.pending_dials)),
+            dests: Arc::new(Mutex::new(HashMap::new())),
+            sockets: Arc::new(Semaphore::new(limits.backend_sockets)),
+        }
+    }
+
+    /// Returns the permit of a new connection, held until it's closed,
+    /// `None` if there are as many connections as allowed.
+    pub fn connection(&self) -> Option<OwnedSemaphorePermit> {
+        self.connections.clone().try_acquire_owned().ok()
+    }
+
+    /// Returns the permit of a dial to `dest`, held until connected.
+    pub fn dial(&self, dest: &Destination) -> Result<Dial, TlvError> {
+        let permit = self
+            .dials
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| limit_reached("too many pending connections"))?;
+
+        let mut dests = self.dests.lock().unwrap();
+        let pending = dests.entry(dest.clone()).or_insert(0);
+        if *pending >= self.limits.pending_dials_per_dest {
+            return Err(limit_reached(format!(
+                "too many pending connections to {}",
+                dest
+            )));
+        }
+        *pending += 1;
+
+        Ok(Dial {
+            _permit: permit,
+            dest: dest.clone(),
+            dests: self.dests.clone(),
+        })
+    }
+
+    /// Returns the permit of a socket towards a destination,
+    /// held until it's closed.
+    pub fn socket(&self) -> Result<OwnedSemaphorePermit, TlvError> {
+        self.sockets
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| limit_reached("too many open sockets"))
+    }
+}
+
+/// Permit of a dial in progress, released when dropped.
+#[derive(Debug)]
+pub struct Dial {
+    _permit: OwnedSemaphorePermit,
+    dest: Destination,
+    dests: Arc<Mutex<HashMap<Destination, usize>>>,
+}
+
+impl Drop for Dial {
+    fn drop(&mut self) {
+        let mut dests = self.dests.lock().unwrap();
+        if let Some(pending) = dests.get_mut(&self.dest) {
+            *pending -= 1;
+            if *pending == 0 {
+                dests.remove(&self.dest);
+            }
+        }
+    }
+}
+
+/// Parses a limit, refusing 0 which would refuse everything,
+/// and those a semaphore can't hold.
+pub fn parse_limit(s: &str) -> Result<usize> {
+    let limit = s.parse().map_err(|_| anyhow!("invalid limit {}", s))?;
+    ensure!(limit > 0, "limit must be positive");
+    ensure!(limit <= MAX_LIMIT, "limit {} is above {}", limit, MAX_LIMIT);
+    Ok(limit)
+}
+
+fn limit_reached(reason: impl Into<String>) -> TlvError {
+    TlvError::new(tlv::ERROR_TYPE_RESOURCE_LIMIT, reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn admission() -> Admission {
+        Admission::new(Limits {
+            connections: 1,
+            streams_per_connection: 2,
+            pending_dials: 3,
+            pending_dials_per_dest: 2,
+            backend_sockets: 1,
+        })
+    }
+
+    #[test]
+    fn connection_test() {
+        let admission = admission();
+        let permit = admission.connection().unwrap();
+        assert!(admission.connection().is_none());
+        drop(permit);
+        assert!(admission.connection().is_some());
+    }
+
+    #[test]
+    fn dial_test() {
+        let admission = admission();
+        let db = Destination::Host("db.internal".to_string(), 5432);
+        let web = Destination::Addr("10.0.0.1:80".parse().unwrap());
+
+        let first = admission.dial(&db).unwrap();
+        let second = admission.dial(&db).unwrap();
+        let err = admission.dial(&db).unwrap_err();
+        assert_eq!(tlv::ERROR_TYPE_RESOURCE_LIMIT, err.code);
+
+        // a refused dial doesn't hold a permit
+        let other = admission.dial(&web).unwrap();
+        assert!(admission.dial(&web).is_err());
+
+        drop(first);
+        let third = admission.dial(&db).unwrap();
+        drop((second, third, other));
+        assert!(admission.dests.lock().unwrap().is_empty());
+        assert_eq!(3, admission.dials.available_permits());
+    }
+
+    #[test]
+    fn parse_limit_test() {
+        assert_eq!(256, parse_limit("256").unwrap());
+        assert_eq!(MAX_LIMIT, parse_limit(&MAX_LIMIT.to_string()).unwrap());
+        assert!(parse_limit(&(MAX_LIMIT + 1).to_string()).is_err());
+        assert!(parse_limit(&usize::MAX.to_string()).is_err());
+        assert!(parse_limit("0").is_err());
+        assert!(parse_limit("-1").is_err());
+        assert!(parse_limit("many").is_err());
+    }
+
+    #[test]
+    fn socket_test() {
+        let admission = admission();
+        let socket = admission.socket().unwrap();
+        let err = admission.socket().unwrap_err();
+        assert_eq!(tlv::ERROR_TYPE_RESOURCE_LIMIT, err.code);
+        drop(socket);
+        assert!(admission.socket().is_ok());
+    }
+}
@@ -1,18 +1,60 @@
-use crate::quic_tunnel::tlv;
+use crate::acl::Acl;
+use crate::frontend::Frontend;
+use crate::quic_tunnel::admission::{Admission, Dial};
+use crate::quic_tunnel::destination::Destination;
+use crate::quic_tunnel::dial;
+use crate::quic_tunnel::ratelimit::{ClientLimits, Limiter};
+use crate::quic_tunnel::reverse::Reverse;
+use crate::quic_tunnel::sockopt;
+use crate::quic_tunnel::tlv::{self, TcpOption, Tlv, TlvError};
+use crate::quic_tunnel::udp::Flows;
+use crate::quic_tunnel::version::Version;
 use crate::Shutdown;
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
 use quinn::{RecvStream, SendStream, VarInt};
 use std::net::SocketAddr;
+use std::sync::Arc;
 use tokio::net::{tcp, TcpStream};
 use tokio::prelude::*;
-use tokio::sync::{broadcast, mpsc};
-use tracing::{debug, error, instrument};
+use tokio::sync::{broadcast, mpsc, OwnedSemaphorePermit};
+use tokio::time::{self, Duration, Instant};
+use tracing::{debug, error, info, instrument};
 
 // tcp payload size based on 1500 MTU
 const TCP_BUF_SIZE: usize = 1480;
 const QUIC_BUF_SIZE: usize = 1480;
 
 pub struct Connection {
+    /// Tunnel protocol version negotiated on the quic connection
+    pub version: Version,
+
+    /// Time allowed to exchange the TLVs up to TCP Connect OK,
+    /// including the frontend's negotiation on the client.
+    pub handshake_timeout: Duration,
+
+    /// Reverse tunnels the client may open on the concentrator,
+    /// `None` if they are disabled.
+    pub reverse: Option<Reverse>,
+
+    /// Destinations the concentrator may ask the client to connect to,
+    /// `None` if any destination is allowed.
+    pub reverse_dests: Option<Arc<Vec<Destination>>>,
+
+    /// UDP flows of the quic connection the client may open,
+    /// `None` if they are disabled.
+    pub udp: Option<Flows>,
+
+    /// Destinations the concentrator may connect to, checked once resolved,
+    /// `None` if any destination is allowed.
+    pub acl: Option<Arc<Acl>>,
+
+    /// Rate limits of the streams the client opens, unlimited by default.
+    pub limits: ClientLimits,
+
+    /// Caps on the concentrator's dials and sockets, `None` if unlimited.
+    pub admission: Option<Arc<Admission>>,
+
     pub shutdown: Shutdown,
 
     // when `Connection` is dropped it
@@ -23,6 +65,7 @@ pub struct Connection {
 struct QuicToTcp {
     pub quic_recv: quinn::RecvStream,
     pub tcp_write: tcp::OwnedWriteHalf,
+    pub limiter: Limiter,
     pub shutdown: Shutdown,
     pub _shutdown_complete: mpsc::Sender<()>,
 }
@@ -30,6 +73,7 @@ struct QuicToTcp {
 struct TcpToQuic {
     pub tcp_read: tcp::OwnedReadHalf,
     pub quic_send: quinn::SendStream,
+    pub limiter: Limiter,
     pub shutdown: Shutdown,
     pub _shutdown_complete: mpsc::Sender<()>,
 }
@@ -37,61 +81,100 @@ struct TcpToQuic {
 impl Connection {
     pub async fn run_client_conn(
         &mut self,
-        tcp_dest_addr: SocketAddr,
-        tcp_streamer: TcpStream,
+        frontend: Frontend,
+        mut tcp_streamer: TcpStream,
         mut quic_send: SendStream,
         mut quic_recv: RecvStream,
     ) -> Result<()> {
         let (notify_shutdown, _) = broadcast::channel(1);
         let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
 
-        let mut buf = [0; 20];
+        let deadline = Instant::now() + self.handshake_timeout;
+
+        let accepted = time::timeout_at(deadline, frontend.accept(&mut tcp_streamer)).await;
+        let (tcp_dest, payload) = match accepted {
+            Ok(Ok(r)) => r,
+            Ok(Err(e)) => {
+                debug!("unable to get tcp destination {}", e);
+                return Ok(());
+            }
+            Err(_) => {
+                debug!("timed out waiting for the tcp destination");
+                return Ok(());
+            }
+        };
+
         // send TCP Connect TLV
-        let n = tlv::new_tcp_connect(&mut buf, &tcp_dest_addr);
-        if let Err(e) = n {
-            error!("error while creating tcp connect tlv {}", e);
-            return Ok(());
-        }
-        if let Err(e) = quic_send.write_all(&buf[..n.unwrap()]).await {
-            error!("error sending tcp connect data to quic stream {}", e);
+        // carry the TCP options of the client's SYN when we can read them
+        let options = if self.version.supports_extended_connect() {
+            sockopt::syn_options(&tcp_streamer)
+        } else {
+            Vec::new()
         };
+        let connect = connect_tlv(self.version, &tcp_dest, options).await;
+        let connect = match connect {
+            Ok(t) => t,
+            Err(e) => {
+                error!("error while creating tcp connect tlv {}", e);
+                let e = TlvError::protocol_violation(e.to_string());
+                return frontend.refuse(tcp_streamer, &e).await;
+            }
+        };
+        let mut buf = BytesMut::new();
+        connect.encode(&mut buf);
 
-        //  End TLV
-        let n = tlv::new_tcp_connect_ok(&mut buf);
-        if let Err(e) = n {
-            error!("error while creating tcp ok connect tlv {}", e);
-            return Ok(());
-        }
-        if let Err(e) = quic_send.write_all(&buf[..n.unwrap()]).await {
+        // End TLV
+        Tlv::End.encode(&mut buf);
+
+        // data the tcp client sent along with its request
+        buf.extend_from_slice(&payload);
+
+        if let Err(e) = quic_send.write_all(&buf).await {
             error!("error sending tcp connect data to quic stream {}", e);
         };
 
         // wait for TCP Connect OK TLV
-        let quic_read_count = quic_recv.read(&mut buf).await;
-        if let Err(ref e) = quic_read_count {
-            error!("error reading quic stream {}", e);
-            tcp_streamer.shutdown(std::net::Shutdown::Both)?;
-            return Ok(());
-        }
-        let quic_read_count = quic_read_count.unwrap();
-
-        if quic_read_count.is_none() {
-            tcp_streamer.shutdown(std::net::Shutdown::Both)?;
-            return Ok(());
+        let mut decoder = tlv::Decoder::new();
+        let reply = time::timeout_at(deadline, read_tlv(&mut quic_recv, &mut decoder)).await;
+        let reply =
+            reply.unwrap_or_else(|_| Err(anyhow!("timed out waiting for TCP Connect OK TLV")));
+        let err = match reply {
+            Ok(Some(Tlv::TcpConnectOk)) => None,
+            Ok(Some(Tlv::Error(e))) => {
+                error!("concentrator failed to connect to {}: {}", tcp_dest, e);
+                Some(e)
+            }
+            Ok(Some(t)) => {
+                error!("expected TCP Connect OK TLV received {:?}", t);
+                Some(TlvError::protocol_violation("expected TCP Connect OK TLV"))
+            }
+            Ok(None) => Some(TlvError::new(
+                tlv::ERROR_TYPE_NETWORK_FAILURE,
+                "quic stream finished",
+            )),
+            Err(e) => {
+                error!("error reading quic stream {}", e);
+                Some(TlvError::new(
+                    tlv::ERROR_TYPE_NETWORK_FAILURE,
+                    e.to_string(),
+                ))
+            }
+        };
+        if let Some(e) = err {
+            return frontend.refuse(tcp_streamer, &e).await;
         }
+        frontend.connected(&mut tcp_streamer).await?;
 
-        let n = quic_read_count.unwrap();
-
-        if !tlv::is_tcp_connect_ok(&buf[..n]) {
-            tcp_streamer.shutdown(std::net::Shutdown::Both)?;
-            return Ok(());
-        }
+        // data sent by the tcp server may arrive together with the TLV
+        tcp_streamer.write_all(&decoder.into_payload()).await?;
 
         let (tcp_read, tcp_write) = tcp_streamer.into_split();
+        let (upload, download) = self.limits.stream();
 
         let mut quic_to_tcp = QuicToTcp {
             quic_recv,
             tcp_write,
+            limiter: download,
             shutdown: Shutdown::new(notify_shutdown.subscribe()),
             _shutdown_complete: shutdown_complete_tx.clone(),
         };
@@ -99,6 +182,7 @@ impl Connection {
         let mut tcp_to_quic = TcpToQuic {
             tcp_read,
             quic_send,
+            limiter: upload,
             shutdown: Shutdown::new(notify_shutdown.subscribe()),
             _shutdown_complete: shutdown_complete_tx.clone(),
         };
@@ -134,50 +218,115 @@ impl Connection {
         let (notify_shutdown, _) = broadcast::channel(1);
         let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
 
-        // wait for quic tunnel tlv
-        let mut buf = [0; 1024];
-        let n = quic_recv.read(&mut buf).await;
-        // TODO:parse tlv
-        if let Err(ref e) = n {
-            error!("error reading quic tlv stream close TCP connection?{}", e);
-            return Ok(());
-        }
-        let remote_addr = match n.unwrap() {
-            Some(_) => tlv::parse_tcp_connect(&buf),
-            None => {
-                // the quic stream is finished close TCP connection
+        // wait for quic tunnel TLVs up to the End TLV,
+        // anything after it is tcp payload
+        let mut decoder = tlv::Decoder::new();
+        let request = time::timeout(
+            self.handshake_timeout,
+            read_connect_request(self.version, &mut quic_recv, &mut decoder),
+        )
+        .await;
+        let request = request.unwrap_or_else(|_| {
+            Err(TlvError::protocol_violation("timed out waiting for End TLV").into())
+        });
+        let (kind, remote_dest, options) = match request {
+            Ok(Some(r)) => r,
+            Ok(None) => {
+                debug!("answering health check");
+                return write_tlv(&mut quic_send, &Tlv::TcpConnectOk).await;
+            }
+            Err(e) => {
+                error!("error reading quic tlv stream close TCP connection?{}", e);
+                if let Some(e) = e.downcast_ref::<TlvError>() {
+                    write_tlv(&mut quic_send, &Tlv::Error(e.clone())).await?;
+                }
                 return Ok(());
             }
         };
+        let payload = decoder.into_payload();
 
-        if let Err(e) = remote_addr {
-            error!(" TCP Connect TLV parse error  {}", e);
-            return Ok(());
+        match (kind, self.reverse.clone(), self.udp.clone()) {
+            (Kind::Connect, ..) => {}
+            (Kind::ReverseListen(port), Some(reverse), _) => {
+                return self
+                    .run_reverse_listener(reverse, port, remote_dest, quic_send, quic_recv)
+                    .await;
+            }
+            (Kind::ReverseListen(port), None, _) => {
+                error!("refusing reverse tunnel on port {}", port);
+                let e = TlvError::protocol_violation("reverse tunnels are disabled");
+                return write_tlv(&mut quic_send, &Tlv::Error(e)).await;
+            }
+            (Kind::UdpAssociate, _, Some(flows)) => {
+                return self
+                    .run_udp_flow(flows, remote_dest, quic_send, quic_recv)
+                    .await;
+            }
+            (Kind::UdpAssociate, _, None) => {
+                error!("refusing udp flow to {}", remote_dest);
+                let e = TlvError::protocol_violation("udp flows are disabled");
+                return write_tlv(&mut quic_send, &Tlv::Error(e)).await;
+            }
+        }
+
+        // the concentrator only opens streams for the client's reverse tunnels
+        if let Some(dests) = &self.reverse_dests {
+            if !dests.contains(&remote_dest) {
+                error!("{} is not a reverse tunnel destination", remote_dest);
+                let e = TlvError::protocol_violation(format!(
+                    "{} is not a reverse tunnel destination",
+                    remote_dest
+                ));
+                return write_tlv(&mut quic_send, &Tlv::Error(e)).await;
+            }
         }
+
+        let admitted = match self.admit(&remote_dest) {
+            Ok(admitted) => admitted,
+            Err(e) => {
+                error!("refusing connection to {} {}", remote_dest, e);
+                return write_tlv(&mut quic_send, &Tlv::Error(e)).await;
+            }
+        };
+
+        let addrs = match self.resolve(&remote_dest).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                error!("unable to connect to {} {}", remote_dest, e);
+                return write_tlv(&mut quic_send, &Tlv::Error(e)).await;
+            }
+        };
+
         // initiate tcp connection
-        let remote_addr = remote_addr.unwrap();
-        let dest_tcp = TcpStream::connect(&remote_addr).await;
+        let dest_tcp = dial::connect(addrs, &options).await;
+        // the socket's permit is held until the stream ends
+        let _socket = admitted.map(|(socket, _dial)| socket);
 
         // If unable to connect to remote tcp destination return error tlv
         if let Err(e) = dest_tcp {
             error!(
                 "unable to establish tcp connection to {} err: {}",
-                remote_addr, e
+                remote_dest, e
             );
-            let n = tlv::new_error_tlv(&mut buf).unwrap();
-            quic_send.write(&mut buf[..n]).await?;
+            let e = TlvError::from_io_error(&e);
+            write_tlv(&mut quic_send, &Tlv::Error(e)).await?;
             return Ok(());
         }
 
         // send TCP Connect OK TLV
-        let n = tlv::new_tcp_connect_ok(&mut buf).unwrap();
-        quic_send.write(&mut buf[..n]).await?;
+        write_tlv(&mut quic_send, &Tlv::TcpConnectOk).await?;
+
+        // forward data received together with the TLVs
+        let mut dest_tcp = dest_tcp.unwrap();
+        dest_tcp.write_all(&payload).await?;
 
-        let (tcp_read, tcp_write) = dest_tcp.unwrap().into_split();
+        let (tcp_read, tcp_write) = dest_tcp.into_split();
+        let (upload, download) = self.limits.stream();
 
         let mut quic_to_tcp = QuicToTcp {
             quic_recv,
             tcp_write,
+            limiter: upload,
             shutdown: Shutdown::new(notify_shutdown.subscribe()),
             _shutdown_complete: shutdown_complete_tx.clone(),
         };
@@ -185,6 +334,7 @@ impl Connection {
         let mut tcp_to_quic = TcpToQuic {
             tcp_read,
             quic_send,
+            limiter: download,
             shutdown: Shutdown::new(notify_shutdown.subscribe()),
             _shutdown_complete: shutdown_complete_tx.clone(),
         };
@@ -211,6 +361,437 @@ impl Connection {
         };
         Ok(())
     }
+
+    /// Listen on `port` of the concentrator until the client closes the stream
+    /// of the request, tunneling accepted connections back to the client's `dest`.
+    async fn run_reverse_listener(
+        &mut self,
+        reverse: Reverse,
+        port: u16,
+        dest: Destination,
+        mut quic_send: SendStream,
+        mut quic_recv: RecvStream,
+    ) -> Result<()> {
+        let (notify_shutdown, _) = broadcast::channel(1);
+        let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
+
+        let _listener_socket = match self.socket() {
+            Ok(permit) => permit,
+            Err(e) => {
+                error!("refusing reverse tunnel on port {} {}", port, e);
+                return write_tlv(&mut quic_send, &Tlv::Error(e)).await;
+            }
+        };
+        let mut listener = match reverse.bind(port).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                error!("unable to listen on reverse tunnel port {} {}", port, e);
+                return write_tlv(&mut quic_send, &Tlv::Error(e)).await;
+            }
+        };
+        write_tlv(&mut quic_send, &Tlv::TcpConnectOk).await?;
+        info!(
+            "forwarding {} to {} on the client",
+            listener.local_addr()?,
+            dest
+        );
+
+        let mut buf = [0; QUIC_BUF_SIZE];
+        // seconds to pause after failing to accept, e.g. when out of file descriptors
+        let mut backoff = 1;
+        while !self.shutdown.is_shutdown() {
+            tokio::select! {
+                accepted = listener.accept() => {
+                    let socket = match accepted {
+                        Ok((socket, _)) => {
+                            backoff = 1;
+                            socket
+                        }
+                        Err(e) => {
+                            error!("unable to accept on reverse tunnel port {} {}", port, e);
+                            tokio::select! {
+                                _ = time::delay_for(Duration::from_secs(backoff)) => {}
+                                _ = self.shutdown.recv() => break,
+                            }
+                            backoff = (backoff * 2).min(64);
+                            continue;
+                        }
+                    };
+                    // dropping `socket` closes the connection
+                    let permit = match self.socket() {
+                        Ok(permit) => permit,
+                        Err(e) => {
+                            error!("refusing connection on reverse tunnel port {} {}", port, e);
+                            continue;
+                        }
+                    };
+
+                    let connection = reverse.connection.clone();
+                    let frontend = Frontend::Forward(dest.clone());
+                    let mut conn = Connection {
+                        version: self.version,
+                        handshake_timeout: self.handshake_timeout,
+                        reverse: None,
+                        reverse_dests: None,
+                        udp: None,
+                        acl: None,
+                        limits: self.limits.reversed(),
+                        admission: None,
+                        shutdown: Shutdown::new(notify_shutdown.subscribe()),
+                        _shutdown_complete: shutdown_complete_tx.clone(),
+                    };
+                    // Spawn a new task to open the stream to the client and process it.
+                    tokio::spawn(async move {
+                        let _permit = permit;
+                        let opened = time::timeout(conn.handshake_timeout, connection.open_bi()).await;
+                        let (quic_send, quic_recv) = match opened {
+                            Ok(Ok(stream)) => stream,
+                            Ok(Err(e)) => {
+                                error!("unable to open quic stream to the client {}", e);
+                                return;
+                            }
+                            Err(_) => {
+                                error!("timed out opening quic stream to the client");
+                                return;
+                            }
+                        };
+                        if let Err(err) = conn.run_client_conn(frontend, socket, quic_send, quic_recv).await {
+                            error!(cause = ? err, "reverse stream error");
+                        }
+                    });
+                }
+
+                // the client stops the reverse tunnel by closing the stream
+                read = quic_recv.read(&mut buf) => {
+                    if let Ok(Some(_)) = read {
+                        continue;
+                    }
+                    debug!("reverse tunnel on port {} closed by the client", port);
+                    break;
+                }
+
+                // wait for shutdown signal
+                _ = self.shutdown.recv() => break,
+            }
+        }
+
+        drop(listener);
+        drop(notify_shutdown);
+        drop(shutdown_complete_tx);
+        let _ = shutdown_complete_rx.recv().await;
+        Ok(())
+    }
+
+    /// Take the permit of a socket towards a destination, held until it's closed,
+    /// `None` without admission control.
+    fn socket(&self) -> std::result::Result<Option<OwnedSemaphorePermit>, TlvError> {
+        self.admission.as_ref().map(|a| a.socket()).transpose()
+    }
+
+    /// Take the permits of a socket towards `dest` and of its dial,
+    /// `None` without admission control.
+    /// Returns the error to send back to the client if a limit is reached.
+    fn admit(
+        &self,
+        dest: &Destination,
+    ) -> std::result::Result<Option<(OwnedSemaphorePermit, Dial)>, TlvError> {
+        match &self.admission {
+            Some(admission) => Ok(Some((admission.socket()?, admission.dial(dest)?))),
+            None => Ok(None),
+        }
+    }
+
+    /// Resolve `dest` and keep the addresses the ACL allows.
+    /// Returns the error to send back to the client if none is left.
+    async fn resolve(&self, dest: &Destination) -> std::result::Result<Vec<SocketAddr>, TlvError> {
+        let addrs = dial::resolve(dest)
+            .await
+            .map_err(|e| TlvError::from_io_error(&e))?;
+        match &self.acl {
+            Some(acl) => acl.filter(dest, addrs),
+            None => Ok(addrs),
+        }
+    }
+
+    /// Relay datagrams between the UDP flow negotiated on the stream and `dest`,
+    /// until either side closes the stream or the flow is idle.
+    async fn run_udp_flow(
+        &mut self,
+        flows: Flows,
+        dest: Destination,
+        mut quic_send: SendStream,
+        mut quic_recv: RecvStream,
+    ) -> Result<()> {
+        let admitted = match self.admit(&dest) {
+            Ok(admitted) => admitted,
+            Err(e) => {
+                error!("refusing udp flow to {} {}", dest, e);
+                return write_tlv(&mut quic_send, &Tlv::Error(e)).await;
+            }
+        };
+        let addrs = match self.resolve(&dest).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                error!("unable to open udp flow to {} {}", dest, e);
+                return write_tlv(&mut quic_send, &Tlv::Error(e)).await;
+            }
+        };
+        let socket = dial::connect_udp(&addrs).await;
+        // the socket's permit is held until the flow ends
+        let _socket = admitted.map(|(socket, _dial)| socket);
+        let socket = match socket {
+            Ok(socket) => socket,
+            Err(e) => {
+                error!("unable to open udp flow to {} err: {}", dest, e);
+                let e = TlvError::from_io_error(&e);
+                return write_tlv(&mut quic_send, &Tlv::Error(e)).await;
+            }
+        };
+        let (mut udp_recv, mut udp_send) = socket.split();
+
+        let (id, mut datagrams) = flows.open();
+        write_tlv(&mut quic_send, &Tlv::UdpFlow(id)).await?;
+        debug!("udp flow {} to {} opened", id, dest);
+
+        // one byte more than a datagram carries, so larger datagrams are
+        // dropped by `Flows::send` rather than truncated
+        let max = flows.max_payload_size().unwrap_or(QUIC_BUF_SIZE);
+        let mut udp_buf = vec![0; max + 1];
+        let mut quic_buf = [0; QUIC_BUF_SIZE];
+        let (upload, download) = self.limits.stream();
+        while !self.shutdown.is_shutdown() {
+            tokio::select! {
+                n = udp_recv.recv(&mut udp_buf) => match n {
+                    Ok(n) => {
+                        // the rate limit doesn't hold back shutdown
+                        tokio::select! {
+                            _ = download.consume(n) => {}
+                            _ = self.shutdown.recv() => break,
+                        }
+                        flows.send(id, &udp_buf[..n]);
+                    }
+                    // e.g. ICMP port unreachable, the destination may come back
+                    Err(e) => debug!("error reading udp flow {} {}", id, e),
+                },
+                Some(payload) = datagrams.recv() => {
+                    tokio::select! {
+                        _ = upload.consume(payload.len()) => {}
+                        _ = self.shutdown.recv() => break,
+                    }
+                    if let Err(e) = udp_send.send(&payload).await {
+                        debug!("error writing udp flow {} {}", id, e);
+                    }
+                }
+
+                // the client closes the stream to end the flow
+                read = quic_recv.read(&mut quic_buf) => {
+                    if let Ok(Some(_)) = read {
+                        continue;
+                    }
+                    debug!("udp flow {} closed by the client", id);
+                    break;
+                }
+
+                _ = time::delay_for(flows.idle_timeout) => {
+                    debug!("udp flow {} to {} expired", id, dest);
+                    break;
+                }
+
+                // wait for shutdown signal
+                _ = self.shutdown.recv() => break,
+            }
+        }
+
+        flows.remove(id);
+        if let Err(e) = quic_send.finish().await {
+            debug!("error closing udp flow stream {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// Build the TLV requesting `dest`.
+/// Host names are resolved locally when `version` can't carry them.
+pub(crate) async fn connect_tlv(
+    version: Version,
+    dest: &Destination,
+    options: Vec<TcpOption>,
+) -> Result<Tlv> {
+    match dest {
+        Destination::Addr(addr) if options.is_empty() => Tlv::tcp_connect(*addr),
+        Destination::Addr(addr) => Tlv::tcp_extended_connect(*addr, options),
+        Destination::Host(host, port) if version.supports_hostname() => {
+            Tlv::tcp_connect_hostname(host, *port, options)
+        }
+        Destination::Host(host, port) => {
+            let addr = tokio::net::lookup_host((host.as_str(), *port))
+                .await?
+                .next()
+                .ok_or_else(|| anyhow!("unable to resolve {}", dest))?;
+            debug!(
+                "{} doesn't carry host names, {} resolved locally to {}",
+                version, dest, addr
+            );
+            if options.is_empty() {
+                Tlv::tcp_connect(addr)
+            } else {
+                Tlv::tcp_extended_connect(addr, options)
+            }
+        }
+    }
+}
+
+/// What the client asks for with the TLV preceding the destination.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// A TCP connection to the destination
+    Connect,
+    /// Listen on a concentrator port for a reverse tunnel to the destination
+    ReverseListen(u16),
+    /// A UDP flow to the destination
+    UdpAssociate,
+}
+
+/// Destination requested by the client's TLVs.
+#[derive(Debug)]
+struct ConnectRequest {
+    version: Version,
+    kind: Kind,
+    dest: Option<(Destination, Vec<TcpOption>)>,
+}
+
+impl ConnectRequest {
+    fn new(version: Version) -> ConnectRequest {
+        ConnectRequest {
+            version,
+            kind: Kind::Connect,
+            dest: None,
+        }
+    }
+
+    /// Add a TLV received from the client.
+    /// Returns `true` once the End TLV terminating the request is received.
+    fn add(&mut self, tlv: Tlv) -> std::result::Result<bool, TlvError> {
+        match tlv {
+            Tlv::TcpConnect(..) | Tlv::TcpExtendedConnect(..) | Tlv::TcpConnectHostname { .. }
+                if self.dest.is_some() =>
+            {
+                Err(TlvError::protocol_violation("duplicate TCP Connect TLV"))
+            }
+            Tlv::TcpConnect(addr) => {
+                self.dest = Some((addr.into(), Vec::new()));
+                Ok(false)
+            }
+            Tlv::TcpExtendedConnect(..) if !self.version.supports_extended_connect() => {
+                Err(TlvError::malformed(format!(
+                    "TCP Extended Connect TLV is not supported by {}",
+                    self.version
+                )))
+            }
+            Tlv::TcpExtendedConnect(addr, options) => {
+                self.dest = Some((addr.into(), options));
+                Ok(false)
+            }
+            Tlv::TcpConnectHostname { .. } if !self.version.supports_hostname() => {
+                Err(TlvError::malformed(format!(
+                    "TCP Connect Hostname TLV is not supported by {}",
+                    self.version
+                )))
+            }
+            Tlv::TcpConnectHostname {
+                host,
+                port,
+                options,
+            } => {
+                self.dest = Some((Destination::Host(host, port), options));
+                Ok(false)
+            }
+            Tlv::TcpReverseListen(_) if !self.version.supports_reverse() => {
+                Err(TlvError::malformed(format!(
+                    "TCP Reverse Listen TLV is not supported by {}",
+                    self.version
+                )))
+            }
+            Tlv::TcpReverseListen(_) if self.kind != Kind::Connect || self.dest.is_some() => Err(
+                TlvError::protocol_violation("TCP Reverse Listen TLV must come first"),
+            ),
+            Tlv::TcpReverseListen(port) => {
+                self.kind = Kind::ReverseListen(port);
+                Ok(false)
+            }
+            Tlv::UdpAssociate if !self.version.supports_udp() => Err(TlvError::malformed(format!(
+                "UDP Associate TLV is not supported by {}",
+                self.version
+            ))),
+            Tlv::UdpAssociate if self.kind != Kind::Connect || self.dest.is_some() => Err(
+                TlvError::protocol_violation("UDP Associate TLV must come first"),
+            ),
+            Tlv::UdpAssociate => {
+                self.kind = Kind::UdpAssociate;
+                Ok(false)
+            }
+            Tlv::End if self.dest.is_none() => {
+                Err(TlvError::protocol_violation("missing TCP Connect TLV"))
+            }
+            Tlv::End => Ok(true),
+            t => Err(TlvError::protocol_violation(format!(
+                "unexpected TLV {:?}",
+                t
+            ))),
+        }
+    }
+}
+
+/// Reads the client's TLVs up to the End TLV.
+/// Returns what the client asks for, the destination and its TCP options,
+/// `None` for a health check made of the End TLV alone, from `tcpquic/4`.
+async fn read_connect_request(
+    version: Version,
+    quic_recv: &mut RecvStream,
+    decoder: &mut tlv::Decoder,
+) -> Result<Option<(Kind, Destination, Vec<TcpOption>)>> {
+    let mut request = ConnectRequest::new(version);
+    let mut first = true;
+    loop {
+        match read_tlv(quic_recv, decoder).await? {
+            Some(Tlv::End) if first && version.supports_health_check() => return Ok(None),
+            Some(t) => {
+                first = false;
+                if request.add(t)? {
+                    break;
+                }
+            }
+            None => return Err(TlvError::protocol_violation("missing End TLV").into()),
+        }
+    }
+    // `add` only accepts the End TLV after a TCP Connect TLV
+    let (dest, options) = request.dest.unwrap();
+    Ok(Some((request.kind, dest, options)))
+}
+
+/// Reads from the quic stream until `decoder` yields a complete TLV.
+/// Returns `None` if the stream is finished before that.
+pub(crate) async fn read_tlv(
+    quic_recv: &mut RecvStream,
+    decoder: &mut tlv::Decoder,
+) -> Result<Option<Tlv>> {
+    let mut buf = [0; QUIC_BUF_SIZE];
+    loop {
+        if let Some(t) = decoder.next_tlv()? {
+            return Ok(Some(t));
+        }
+        match quic_recv.read(&mut buf).await? {
+            Some(n) => decoder.extend(&buf[..n]),
+            None => return Ok(None),
+        }
+    }
+}
+
+pub(crate) async fn write_tlv(quic_send: &mut SendStream, tlv: &Tlv) -> Result<()> {
+    let mut buf = BytesMut::new();
+    tlv.encode(&mut buf);
+    quic_send.write_all(&buf).await?;
+    Ok(())
 }
 
 impl TcpToQuic {
@@ -233,6 +814,11 @@ impl TcpToQuic {
                         },
                         Ok(n) => {
                             debug!("tcp data size {}\n", n);
+                            // the rate limit doesn't hold back shutdown
+                            tokio::select! {
+                                _ = self.limiter.consume(n) => {}
+                                _ = self.shutdown.recv() => continue,
+                            }
                             if let Err(e) = self.quic_send.write_all(&tcp_buf[..n]).await {
                                 // handle remote TCP RST
                                 // forced QUIC->TCP shutdown
@@ -252,15 +838,14 @@ impl TcpToQuic {
                 },
 
                 // wait for shutdown signal
-                _ = self.shutdown.recv() => {
-                    debug!("shutdown down TcpToQuic");
-                    if let Err(e) = self.quic_send.finish().await {
-                        debug!("error gracefully shutting send stream {}",e);
-                    }
-                    return Ok(());
-                }
+                _ = self.shutdown.recv() => {}
             };
         }
+
+        debug!("shutdown down TcpToQuic");
+        if let Err(e) = self.quic_send.finish().await {
+            debug!("error gracefully shutting send stream {}", e);
+        }
         Ok(())
     }
 }
@@ -286,6 +871,11 @@ impl QuicToTcp {
 
                     match count.unwrap() {
                         Some(n) => {
+                            // the rate limit doesn't hold back shutdown
+                            tokio::select! {
+                                _ = self.limiter.consume(n) => {}
+                                _ = self.shutdown.recv() => continue,
+                            }
                             if let Err(err) = self.tcp_write.write_all(&quic_buf[..n]).await {
                                 // handle TCP RST
                                 // forced TCP->QUIC shutdown
@@ -314,16 +904,132 @@ impl QuicToTcp {
                 },
 
                 // wait for shutdown signal
-                _ = self.shutdown.recv() => {
-                    debug!("shutdown down QuicToTcp");
-                    let err_code = VarInt::from_u32(0);
-                    if let Err(e) = self.quic_recv.stop(err_code) {
-                        debug!("error closing quic write stream {:?}",e);
-                    }
-                    return Ok(());
-                }
+                _ = self.shutdown.recv() => {}
             };
         }
+
+        debug!("shutdown down QuicToTcp");
+        let err_code = VarInt::from_u32(0);
+        if let Err(e) = self.quic_recv.stop(err_code) {
+            debug!("error closing quic write stream {:?}", e);
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::SocketAddr;
+
+    #[test]
+    fn connect_request_test() {
+        let addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let mut request = ConnectRequest::new(Version::V2);
+
+        assert_eq!(Ok(false), request.add(Tlv::TcpConnect(addr)));
+        assert_eq!(Ok(true), request.add(Tlv::End));
+        assert_eq!(Some((addr.into(), Vec::new())), request.dest);
+    }
+
+    #[test]
+    fn connect_request_violation_test() {
+        let addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let violations: &[&[Tlv]] = &[
+            &[Tlv::End],
+            &[Tlv::TcpConnect(addr), Tlv::TcpConnect(addr)],
+            &[
+                Tlv::TcpConnect(addr),
+                Tlv::TcpExtendedConnect(addr, Vec::new()),
+            ],
+            &[Tlv::TcpConnect(addr), Tlv::TcpConnectOk],
+        ];
+
+        for tlvs in violations {
+            let mut request = ConnectRequest::new(Version::V2);
+            let err = tlvs
+                .iter()
+                .cloned()
+                .map(|t| request.add(t))
+                .find_map(|r| r.err())
+                .unwrap();
+            assert_eq!(tlv::ERROR_TYPE_PROTOCOL_VIOLATION, err.code);
+        }
+    }
+
+    #[test]
+    fn connect_request_hostname_test() {
+        let hostname = Tlv::TcpConnectHostname {
+            host: "db.internal".to_string(),
+            port: 5432,
+            options: Vec::new(),
+        };
+
+        let mut request = ConnectRequest::new(Version::V2);
+        assert!(request.add(hostname.clone()).is_err());
+
+        let mut request = ConnectRequest::new(Version::V3);
+        assert_eq!(Ok(false), request.add(hostname));
+        assert_eq!(Ok(true), request.add(Tlv::End));
+        assert_eq!(
+            Some((
+                Destination::Host("db.internal".to_string(), 5432),
+                Vec::new()
+            )),
+            request.dest
+        );
+    }
+
+    #[test]
+    fn connect_request_version_test() {
+        let addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let extended = Tlv::TcpExtendedConnect(addr, vec![TcpOption::SackPermitted]);
+
+        let mut request = ConnectRequest::new(Version::V1);
+        let err = request.add(extended.clone()).unwrap_err();
+        assert_eq!(tlv::ERROR_TYPE_MALFORMED_TLV_RECV, err.code);
+
+        let mut request = ConnectRequest::new(Version::V2);
+        assert_eq!(Ok(false), request.add(extended));
+    }
+
+    #[test]
+    fn connect_request_reverse_test() {
+        let addr: SocketAddr = "127.0.0.1:22".parse().unwrap();
+
+        let mut request = ConnectRequest::new(Version::V4);
+        let err = request.add(Tlv::TcpReverseListen(2222)).unwrap_err();
+        assert_eq!(tlv::ERROR_TYPE_MALFORMED_TLV_RECV, err.code);
+
+        let mut request = ConnectRequest::new(Version::V5);
+        assert_eq!(Ok(false), request.add(Tlv::TcpReverseListen(2222)));
+        assert_eq!(Ok(false), request.add(Tlv::TcpConnect(addr)));
+        assert_eq!(Ok(true), request.add(Tlv::End));
+        assert_eq!(Kind::ReverseListen(2222), request.kind);
+
+        let mut request = ConnectRequest::new(Version::V5);
+        assert_eq!(Ok(false), request.add(Tlv::TcpConnect(addr)));
+        let err = request.add(Tlv::TcpReverseListen(2222)).unwrap_err();
+        assert_eq!(tlv::ERROR_TYPE_PROTOCOL_VIOLATION, err.code);
+    }
+
+    #[test]
+    fn connect_request_udp_test() {
+        let addr: SocketAddr = "10.0.0.53:53".parse().unwrap();
+
+        let mut request = ConnectRequest::new(Version::V5);
+        let err = request.add(Tlv::UdpAssociate).unwrap_err();
+        assert_eq!(tlv::ERROR_TYPE_MALFORMED_TLV_RECV, err.code);
+
+        let mut request = ConnectRequest::new(Version::V6);
+        assert_eq!(Ok(false), request.add(Tlv::UdpAssociate));
+        assert_eq!(Ok(false), request.add(Tlv::TcpConnect(addr)));
+        assert_eq!(Ok(true), request.add(Tlv::End));
+        assert_eq!(Kind::UdpAssociate, request.kind);
+
+        let mut request = ConnectRequest::new(Version::V6);
+        assert_eq!(Ok(false), request.add(Tlv::TcpReverseListen(2222)));
+        let err = request.add(Tlv::UdpAssociate).unwrap_err();
+        assert_eq!(tlv::ERROR_TYPE_PROTOCOL_VIOLATION, err.code);
+    }
+}
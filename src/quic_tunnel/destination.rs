@@ -0,0 +1,146 @@
+use anyhow::{anyhow, ensure, Result};
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+
+/// Remote TCP destination requested by the client.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Destination {
+    Addr(SocketAddr),
+    /// Domain name and port, resolved by the concentrator.
+    Host(String, u16),
+}
+
+impl Destination {
+    pub fn port(&self) -> u16 {
+        match self {
+            Destination::Addr(addr) => addr.port(),
+            Destination::Host(_, port) => *port,
+        }
+    }
+}
+
+impl From<SocketAddr> for Destination {
+    fn from(addr: SocketAddr) -> Destination {
+        Destination::Addr(addr)
+    }
+}
+
+impl fmt::Display for Destination {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Destination::Addr(addr) => write!(f, "{}", addr),
+            Destination::Host(host, port) => write!(f, "{}:{}", host, port),
+        }
+    }
+}
+
+impl FromStr for Destination {
+    type Err = anyhow::Error;
+
+    /// Parses `ip:port`, `[ipv6]:port` or `host:port`.
+    fn from_str(s: &str) -> Result<Destination> {
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(Destination::Addr(addr));
+        }
+
+        let (host, port) = s
+            .rsplit_once(':')
+            .ok_or_else(|| anyhow!("missing port in destination {}", s))?;
+        let port = port
+            .parse()
+            .map_err(|_| anyhow!("invalid port in destination {}", s))?;
+        let host = host.trim_end_matches('.');
+
+        ensure!(is_valid_host(host), "invalid host in destination {}", s);
+
+        Ok(Destination::Host(host.to_ascii_lowercase(), port))
+    }
+}
+
+/// Domain names are limited to 253 characters of letters, digits, `-`, `_` and `.`
+pub fn is_valid_host(host: &str) -> bool {
+    !host.is_empty()
+        && host.len() <= 253
+        && host.split('.').all(|label| {
+            !label.is_empty()
+                && label.len() <= 63
+                && label
+                    .bytes()
+                    .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        })
+}
+
+/// Parses `port` or `start-end`.
+pub fn parse_port_range(s: &str) -> Result<(u16, u16)> {
+    let (start, end) = s.split_once('-').unwrap_or((s, s));
+    let start: u16 = start
+        .parse()
+        .map_err(|_| anyhow!("invalid port {}", start))?;
+    let end: u16 = end.parse().map_err(|_| anyhow!("invalid port {}", end))?;
+    ensure!(start <= end, "invalid port range {}", s);
+    Ok((start, end))
+}
+
+/// Parses an address to bind to, IPv6 addresses may be in brackets, e.g. `[::]`.
+pub fn parse_bind_ip(s: &str) -> Result<IpAddr> {
+    let ip = s
+        .strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(s);
+    match ip.parse() {
+        Ok(IpAddr::V4(_)) if ip != s => Err(anyhow!("invalid bind address {}", s)),
+        Ok(ip) => Ok(ip),
+        Err(_) => Err(anyhow!("invalid bind address {}", s)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_destination_test() {
+        assert_eq!(
+            Destination::Addr("10.0.0.1:80".parse().unwrap()),
+            "10.0.0.1:80".parse().unwrap()
+        );
+        assert_eq!(
+            Destination::Addr("[::1]:80".parse().unwrap()),
+            "[::1]:80".parse().unwrap()
+        );
+        assert_eq!(
+            Destination::Host("db.internal".to_string(), 5432),
+            "DB.internal.:5432".parse().unwrap()
+        );
+
+        for invalid in &[
+            "db.internal",
+            "db.internal:http",
+            ":80",
+            "::1:80",
+            "a..b:80",
+        ] {
+            assert!(invalid.parse::<Destination>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn parse_bind_ip_test() {
+        assert_eq!(
+            IpAddr::from([0, 0, 0, 0]),
+            parse_bind_ip("0.0.0.0").unwrap()
+        );
+        assert_eq!(
+            "::".parse::<IpAddr>().unwrap(),
+            parse_bind_ip("[::]").unwrap()
+        );
+        assert_eq!(
+            "fd00::1".parse::<IpAddr>().unwrap(),
+            parse_bind_ip("fd00::1").unwrap()
+        );
+        for invalid in &["", "localhost", "[127.0.0.1]", "[::", "10.0.0.1:4433"] {
+            assert!(parse_bind_ip(invalid).is_err(), "{}", invalid);
+        }
+    }
+}
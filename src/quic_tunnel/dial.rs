@@ -0,0 +1,156 @@
+use crate::quic_tunnel::destination::Destination;
+use crate::quic_tunnel::sockopt;
+use crate::quic_tunnel::tlv::TcpOption;
+use futures::stream::{FuturesUnordered, StreamExt};
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use tokio::net::{self, TcpStream, UdpSocket};
+use tokio::time::{self, Duration};
+use tracing::debug;
+
+/// Delay before starting the next connection attempt, as recommended by RFC 8305.
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+type Attempt = Pin<Box<dyn Future<Output = (SocketAddr, io::Result<TcpStream>)> + Send>>;
+
+/// Addresses of `dest`, host names are resolved.
+pub async fn resolve(dest: &Destination) -> io::Result<Vec<SocketAddr>> {
+    match dest {
+        Destination::Addr(addr) => Ok(vec![*addr]),
+        Destination::Host(host, port) => {
+            let addrs: Vec<SocketAddr> = net::lookup_host((host.as_str(), *port)).await?.collect();
+            debug!("{} resolved to {:?}", dest, addrs);
+            Ok(addrs)
+        }
+    }
+}
+
+/// Connect to one of the addresses of a destination applying `options` on the socket.
+/// Addresses are tried in Happy Eyeballs order.
+pub async fn connect(addrs: Vec<SocketAddr>, options: &[TcpOption]) -> io::Result<TcpStream> {
+    match addrs.as_slice() {
+        [addr] => sockopt::connect(*addr, options).await,
+        _ => happy_eyeballs(addrs, options).await,
+    }
+}
+
+/// Bind a UDP socket connected to the first of the addresses of a destination,
+/// as UDP can't tell whether an address answers.
+pub async fn connect_udp(addrs: &[SocketAddr]) -> io::Result<UdpSocket> {
+    let addr = *addrs
+        .first()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address resolved"))?;
+    let unspecified = match addr {
+        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    };
+    let socket = UdpSocket::bind(SocketAddr::new(unspecified, 0)).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+/// Connect to the first address answering, starting a new attempt every
+/// `CONNECTION_ATTEMPT_DELAY` or as soon as the previous one failed.
+async fn happy_eyeballs(addrs: Vec<SocketAddr>, options: &[TcpOption]) -> io::Result<TcpStream> {
+    let mut pending: VecDeque<SocketAddr> = interleave(addrs).into();
+    let mut attempts: FuturesUnordered<Attempt> = FuturesUnordered::new();
+    let mut last_err = None;
+
+    loop {
+        if attempts.is_empty() {
+            match pending.pop_front() {
+                Some(addr) => attempts.push(attempt(addr, options)),
+                None => {
+                    return Err(last_err.unwrap_or_else(|| {
+                        io::Error::new(io::ErrorKind::NotFound, "no address resolved")
+                    }))
+                }
+            }
+        }
+
+        tokio::select! {
+            Some((addr, res)) = attempts.next() => match res {
+                Ok(stream) => return Ok(stream),
+                Err(e) => {
+                    debug!("connection attempt to {} failed {}", addr, e);
+                    last_err = Some(e);
+                    if let Some(addr) = pending.pop_front() {
+                        attempts.push(attempt(addr, options));
+                    }
+                }
+            },
+            _ = time::delay_for(CONNECTION_ATTEMPT_DELAY), if !pending.is_empty() => {
+                attempts.push(attempt(pending.pop_front().unwrap(), options));
+            }
+        }
+    }
+}
+
+fn attempt(addr: SocketAddr, options: &[TcpOption]) -> Attempt {
+    let options = options.to_vec();
+    Box::pin(async move { (addr, sockopt::connect(addr, &options).await) })
+}
+
+/// Order addresses alternating between IPv6 and IPv4, starting with IPv6.
+fn interleave(addrs: Vec<SocketAddr>) -> Vec<SocketAddr> {
+    let (mut v6, mut v4): (VecDeque<_>, VecDeque<_>) = addrs.into_iter().partition(|a| a.is_ipv6());
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    while !v6.is_empty() || !v4.is_empty() {
+        ordered.extend(v6.pop_front());
+        ordered.extend(v4.pop_front());
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[test]
+    fn interleave_test() {
+        let addrs = vec![
+            "10.0.0.1:80".parse().unwrap(),
+            "10.0.0.2:80".parse().unwrap(),
+            "10.0.0.3:80".parse().unwrap(),
+            "[fd00::1]:80".parse().unwrap(),
+        ];
+        let expected: Vec<SocketAddr> = vec![
+            "[fd00::1]:80".parse().unwrap(),
+            "10.0.0.1:80".parse().unwrap(),
+            "10.0.0.2:80".parse().unwrap(),
+            "10.0.0.3:80".parse().unwrap(),
+        ];
+        assert_eq!(expected, interleave(addrs));
+    }
+
+    #[tokio::test]
+    async fn happy_eyeballs_fallback_test() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // nothing listens on the IPv6 loopback so the IPv4 attempt wins
+        let addrs = vec![addr, SocketAddr::new("::1".parse().unwrap(), addr.port())];
+        let (stream, accepted) = tokio::join!(happy_eyeballs(addrs, &[]), listener.accept());
+        assert_eq!(addr, stream.unwrap().peer_addr().unwrap());
+        assert!(accepted.is_ok());
+
+        assert!(happy_eyeballs(vec![], &[]).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn connect_udp_test() {
+        let mut server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+
+        let mut socket = connect_udp(&[addr]).await.unwrap();
+        socket.send(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        let (n, from) = server.recv_from(&mut buf).await.unwrap();
+        assert_eq!(b"ping", &buf[..n]);
+        assert_eq!(socket.local_addr().unwrap().port(), from.port());
+    }
+}
@@ -1,3 +1,19 @@
 pub mod tlv;
 
+pub mod admission;
+
 pub mod connection;
+
+pub mod destination;
+
+pub mod dial;
+
+pub mod ratelimit;
+
+pub mod reverse;
+
+pub mod sockopt;
+
+pub mod udp;
+
+pub mod version;
@@ -0,0 +1,285 @@
+use anyhow::{anyhow, ensure, Result};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::time;
+
+/// Smallest burst, so a whole read of the copy loops always fits in a bucket.
+const MIN_BURST: u64 = 64 * 1024;
+
+/// Rate of a token bucket in bytes per second.
+///
+/// Parsed from a number of bytes with an optional `K`, `M` or `G` suffix,
+/// powers of 1024, e.g. `512K` or `10M`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rate(pub u64);
+
+impl FromStr for Rate {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Rate> {
+        let s = s.trim();
+        let (digits, unit) = match s.char_indices().last() {
+            Some((i, c)) if c.is_ascii_alphabetic() => (&s[..i], c.to_ascii_uppercase()),
+            _ => (s, 'B'),
+        };
+        let multiplier: u64 = match unit {
+            'B' => 1,
+            'K' => 1 << 10,
+            'M' => 1 << 20,
+            'G' => 1 << 30,
+            _ => return Err(anyhow!("invalid rate unit in {}", s)),
+        };
+        let n: u64 = digits.parse().map_err(|_| anyhow!("invalid rate {}", s))?;
+        let rate = n
+            .checked_mul(multiplier)
+            .ok_or_else(|| anyhow!("rate {} is too large", s))?;
+        ensure!(rate > 0, "rate must be positive");
+        Ok(Rate(rate))
+    }
+}
+
+/// Token bucket refilled at a fixed rate, with bursts of up to a second of traffic.
+#[derive(Debug)]
+pub struct Bucket {
+    rate: f64,
+    burst: f64,
+    state: Mutex<(f64, Instant)>,
+}
+
+impl Bucket {
+    pub fn new(rate: Rate) -> Bucket {
+        let burst = rate.0.max(MIN_BURST) as f64;
+        Bucket {
+            rate: rate.0 as f64,
+            burst,
+            state: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Take `n` bytes from the bucket, which may go into debt.
+    /// Returns how long to wait before sending them.
+    fn take(&self, n: usize, now: Instant) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let (tokens, last) = &mut *state;
+        let elapsed = now.saturating_duration_since(*last).as_secs_f64();
+        *tokens = (*tokens + elapsed * self.rate).min(self.burst) - n as f64;
+        *last = now;
+        if *tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-*tokens / self.rate)
+        }
+    }
+}
+
+/// Buckets one direction of a stream draws from, e.g. the concentrator's,
+/// the client's and the stream's own.
+#[derive(Debug, Clone, Default)]
+pub struct Limiter {
+    buckets: Vec<Arc<Bucket>>,
+}
+
+impl Limiter {
+    /// Returns this limiter also drawing from `bucket`.
+    pub fn with(&self, bucket: Option<Arc<Bucket>>) -> Limiter {
+        let mut buckets = self.buckets.clone();
+        buckets.extend(bucket);
+        Limiter { buckets }
+    }
+
+    /// Wait until `n` bytes may be sent.
+    pub async fn consume(&self, n: usize) {
+        let now = Instant::now();
+        let wait = self.buckets.iter().map(|b| b.take(n, now)).max();
+        if let Some(wait) = wait.filter(|w| *w > Duration::from_secs(0)) {
+            time::delay_for(wait).await;
+        }
+    }
+}
+
+/// Rates of a direction, `None` where unlimited.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Rates {
+    /// Shared by every client of the concentrator
+    pub global: Option<Rate>,
+    /// Shared by the connections of a client
+    pub client: Option<Rate>,
+    /// Applied to each stream
+    pub stream: Option<Rate>,
+}
+
+/// Upload and download buckets of a client, dropped with its last connection.
+type ClientBuckets = (Weak<Bucket>, Weak<Bucket>);
+
+/// Rate limits of the concentrator. Upload is the traffic from the clients
+/// to their destinations, download from the destinations to the clients.
+#[derive(Debug, Default)]
+pub struct RateLimits {
+    upload: Rates,
+    download: Rates,
+    global: (Option<Arc<Bucket>>, Option<Arc<Bucket>>),
+    /// Buckets of the clients with open connections
+    clients: Mutex<HashMap<String, ClientBuckets>>,
+}
+
+impl RateLimits {
+    pub fn new(upload: Rates, download: Rates) -> RateLimits {
+        RateLimits {
+            upload,
+            download,
+            global: (
+                upload.global.map(|r| Arc::new(Bucket::new(r))),
+                download.global.map(|r| Arc::new(Bucket::new(r))),
+            ),
+            clients: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the limits of the streams of `client`, its identity or its
+    /// address, shared with its other connections.
+    pub fn client(&self, client: &str) -> ClientLimits {
+        let (upload, download) = if self.upload.client.is_none() && self.download.client.is_none() {
+            (None, None)
+        } else {
+            let mut clients = self.clients.lock().unwrap();
+            match clients.get(client) {
+                Some((upload, download)) if upload.strong_count() + download.strong_count() > 0 => {
+                    (upload.upgrade(), download.upgrade())
+                }
+                _ => {
+                    let upload = self.upload.client.map(|r| Arc::new(Bucket::new(r)));
+                    let download = self.download.client.map(|r| Arc::new(Bucket::new(r)));
+                    // forget the clients without connections left
+                    clients.retain(|_, (u, d)| u.strong_count() + d.strong_count() > 0);
+                    clients.insert(
+                        client.to_string(),
+                        (downgrade(&upload), downgrade(&download)),
+                    );
+                    (upload, download)
+                }
+            }
+        };
+
+        ClientLimits {
+            upload: Limiter::default().with(self.global.0.clone()).with(upload),
+            download: Limiter::default()
+                .with(self.global.1.clone())
+                .with(download),
+            stream: (self.upload.stream, self.download.stream),
+        }
+    }
+}
+
+/// Limits of the streams of a client connection.
+#[derive(Debug, Clone, Default)]
+pub struct ClientLimits {
+    upload: Limiter,
+    download: Limiter,
+    stream: (Option<Rate>, Option<Rate>),
+}
+
+impl ClientLimits {
+    /// Returns the upload and download limiters of a new stream.
+    pub fn stream(&self) -> (Limiter, Limiter) {
+        (
+            self.upload
+                .with(self.stream.0.map(|r| Arc::new(Bucket::new(r)))),
+            self.download
+                .with(self.stream.1.map(|r| Arc::new(Bucket::new(r)))),
+        )
+    }
+
+    /// Returns the limits of the streams carrying connections accepted by the
+    /// concentrator for a reverse tunnel, whose upload comes back from the client.
+    pub fn reversed(&self) -> ClientLimits {
+        ClientLimits {
+            upload: self.download.clone(),
+            download: self.upload.clone(),
+            stream: (self.stream.1, self.stream.0),
+        }
+    }
+}
+
+fn downgrade(bucket: &Option<Arc<Bucket>>) -> Weak<Bucket> {
+    bucket.as_ref().map(Arc::downgrade).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rate_test() {
+        assert_eq!(Rate(1500), "1500".parse().unwrap());
+        assert_eq!(Rate(512 * 1024), "512K".parse().unwrap());
+        assert_eq!(Rate(10 << 20), "10m".parse().unwrap());
+        assert_eq!(Rate(1 << 30), "1G".parse().unwrap());
+        for invalid in &["", "0", "K", "10T", "-1M", "99999999999G"] {
+            assert!(invalid.parse::<Rate>().is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn bucket_test() {
+        let bucket = Bucket::new(Rate(1 << 20));
+        let start = Instant::now();
+        *bucket.state.lock().unwrap() = (bucket.burst, start);
+
+        // the burst is sent right away
+        assert_eq!(Duration::from_secs(0), bucket.take(1 << 20, start));
+        // then at the rate
+        assert_eq!(Duration::from_millis(500), bucket.take(1 << 19, start));
+        let later = start + Duration::from_millis(500);
+        assert_eq!(Duration::from_secs(0), bucket.take(0, later));
+        // tokens don't accumulate above the burst
+        let idle = later + Duration::from_secs(10);
+        assert_eq!(Duration::from_secs(0), bucket.take(1 << 20, idle));
+        assert!(bucket.take(1, idle) > Duration::from_secs(0));
+    }
+
+    #[test]
+    fn client_limits_test() {
+        let limits = RateLimits::new(
+            Rates {
+                client: Some(Rate(1 << 20)),
+                stream: Some(Rate(1 << 10)),
+                ..Rates::default()
+            },
+            Rates {
+                global: Some(Rate(1 << 20)),
+                ..Rates::default()
+            },
+        );
+
+        let alice = limits.client("alice");
+        let (upload, download) = alice.stream();
+        assert_eq!(2, upload.buckets.len());
+        assert_eq!(1, download.buckets.len());
+
+        // connections of a client share its bucket
+        let (other, _) = limits.client("alice").stream();
+        assert!(Arc::ptr_eq(&upload.buckets[0], &other.buckets[0]));
+        assert!(!Arc::ptr_eq(&upload.buckets[1], &other.buckets[1]));
+        let (bob, bob_download) = limits.client("bob").stream();
+        assert!(!Arc::ptr_eq(&upload.buckets[0], &bob.buckets[0]));
+        assert!(Arc::ptr_eq(&download.buckets[0], &bob_download.buckets[0]));
+
+        // and it's dropped along with them
+        drop((alice, upload, other));
+        limits.client("carol");
+        assert!(!limits.clients.lock().unwrap().contains_key("alice"));
+
+        let (reverse_upload, reverse_download) = limits.client("bob").reversed().stream();
+        assert_eq!(1, reverse_upload.buckets.len());
+        assert!(Arc::ptr_eq(
+            &bob_download.buckets[0],
+            &reverse_upload.buckets[0]
+        ));
+        assert!(Arc::ptr_eq(&bob.buckets[0], &reverse_download.buckets[0]));
+
+        let unlimited = RateLimits::default().client("alice").stream();
+        assert!(unlimited.0.buckets.is_empty() && unlimited.1.buckets.is_empty());
+    }
+}
@@ -0,0 +1,167 @@
+use crate::quic_tunnel::connection::{connect_tlv, read_tlv};
+use crate::quic_tunnel::destination::Destination;
+use crate::quic_tunnel::sockopt;
+use crate::quic_tunnel::tlv::{self, Tlv, TlvError};
+use crate::quic_tunnel::version::Version;
+use crate::Shutdown;
+use anyhow::{anyhow, Result};
+use bytes::BytesMut;
+use quinn::{RecvStream, SendStream};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::time::{self, Duration};
+use tracing::{debug, error, info};
+
+/// Delay before registering refused rules again, doubled after each failure.
+const MIN_RETRY: Duration = Duration::from_secs(1);
+const MAX_RETRY: Duration = Duration::from_secs(60);
+
+/// Reverse tunnels the concentrator accepts.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// Ports clients may ask the concentrator to listen on
+    pub ports: RangeInclusive<u16>,
+    pub bind_ip: IpAddr,
+}
+
+/// Concentrator side of the reverse tunnels of a client's quic connection.
+#[derive(Clone)]
+pub struct Reverse {
+    /// Streams of the connections accepted on the reverse tunnels are opened on it
+    pub connection: quinn::Connection,
+    pub config: Arc<Config>,
+}
+
+impl Reverse {
+    /// Bind the listener of a reverse tunnel on `port`.
+    /// Returns the error to send back to the client on failure.
+    pub async fn bind(&self, port: u16) -> std::result::Result<TcpListener, TlvError> {
+        if !self.config.ports.contains(&port) {
+            return Err(TlvError::protocol_violation(format!(
+                "port {} is not allowed for reverse tunnels",
+                port
+            )));
+        }
+        // reuse the port right away when the client reconnects
+        sockopt::tcp_listener(SocketAddr::new(self.config.bind_ip, port))
+            .and_then(TcpListener::from_std)
+            .map_err(|e| TlvError::from_io_error(&e))
+    }
+}
+
+/// Services of the client exposed on ports of the concentrator.
+#[derive(Debug, Clone)]
+pub struct Registration {
+    /// Concentrator port and the client side destination of its connections
+    pub rules: Vec<(u16, Destination)>,
+
+    /// Time allowed for the concentrator's reply to each rule,
+    /// and then to receive the TLVs of the streams it opens.
+    pub handshake_timeout: Duration,
+}
+
+impl Registration {
+    /// Destinations the concentrator may ask the client to connect to.
+    pub fn dests(&self) -> Arc<Vec<Destination>> {
+        Arc::new(self.rules.iter().map(|(_, dest)| dest.clone()).collect())
+    }
+
+    /// Ask the concentrator to listen on the port of every rule, and keep
+    /// the streams of the rules it accepted open until shutdown, the concentrator
+    /// stops listening once they are closed. Refused rules are registered again
+    /// with backoff, e.g. until the concentrator released the ports of the
+    /// client's previous connection.
+    pub async fn run(
+        &self,
+        connection: quinn::Connection,
+        version: Version,
+        mut shutdown: Shutdown,
+    ) {
+        let mut pending = self.rules.clone();
+        let mut streams = Vec::with_capacity(self.rules.len());
+        let mut retry = MIN_RETRY;
+        loop {
+            let refused = tokio::select! {
+                refused = self.register(&connection, version, &pending, &mut streams) => refused,
+                _ = shutdown.recv() => return,
+            };
+            if refused.is_empty() {
+                break;
+            }
+            pending = refused;
+            debug!(
+                "registering {} reverse tunnels again in {:?}",
+                pending.len(),
+                retry
+            );
+            tokio::select! {
+                _ = time::delay_for(retry) => {}
+                _ = shutdown.recv() => return,
+            }
+            retry = (retry * 2).min(MAX_RETRY);
+        }
+        shutdown.recv().await;
+    }
+
+    /// Register `rules`, adding the streams of the accepted ones to `streams`.
+    /// Returns the refused rules.
+    async fn register(
+        &self,
+        connection: &quinn::Connection,
+        version: Version,
+        rules: &[(u16, Destination)],
+        streams: &mut Vec<(SendStream, RecvStream)>,
+    ) -> Vec<(u16, Destination)> {
+        let mut refused = Vec::new();
+        for (port, dest) in rules {
+            match self.register_rule(connection, version, *port, dest).await {
+                Ok(stream) => {
+                    info!("concentrator port {} forwarded to {}", port, dest);
+                    streams.push(stream);
+                }
+                Err(e) => {
+                    error!("unable to forward concentrator port {} {}", port, e);
+                    refused.push((*port, dest.clone()));
+                }
+            }
+        }
+        refused
+    }
+
+    async fn register_rule(
+        &self,
+        connection: &quinn::Connection,
+        version: Version,
+        port: u16,
+        dest: &Destination,
+    ) -> Result<(SendStream, RecvStream)> {
+        let (mut quic_send, mut quic_recv) =
+            time::timeout(self.handshake_timeout, connection.open_bi())
+                .await
+                .map_err(|_| anyhow!("timed out opening quic stream"))??;
+
+        let mut buf = BytesMut::new();
+        Tlv::TcpReverseListen(port).encode(&mut buf);
+        connect_tlv(version, dest, Vec::new())
+            .await?
+            .encode(&mut buf);
+        Tlv::End.encode(&mut buf);
+        quic_send.write_all(&buf).await?;
+
+        let mut decoder = tlv::Decoder::new();
+        let reply = time::timeout(
+            self.handshake_timeout,
+            read_tlv(&mut quic_recv, &mut decoder),
+        )
+        .await
+        .map_err(|_| anyhow!("timed out waiting for TCP Connect OK TLV"))??;
+        match reply {
+            Some(Tlv::TcpConnectOk) => Ok((quic_send, quic_recv)),
+            Some(Tlv::Error(e)) => Err(e.into()),
+            Some(t) => Err(anyhow!("expected TCP Connect OK TLV received {:?}", t)),
+            None => Err(anyhow!("quic stream finished")),
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use crate::quic_tunnel::tlv::TcpOption;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::io;
+use std::net::{IpAddr, SocketAddr, TcpListener, UdpSocket};
+use tokio::net::TcpStream;
+use tracing::debug;
+
+/// Returns the TCP options negotiated on the SYN of an accepted connection.
+///
+/// Linux doesn't keep the raw options of the SYN, so they are rebuilt from
+/// `TCP_MAXSEG` and `TCP_INFO`. Timestamps values are not available and the
+/// option isn't forwarded.
+#[cfg(target_os = "linux")]
+pub fn syn_options(stream: &TcpStream) -> Vec<TcpOption> {
+    use std::os::unix::io::AsRawFd;
+
+    // tcpi_options flags from linux/tcp.h
+    const TCPI_OPT_SACK: u8 = 2;
+    const TCPI_OPT_WSCALE: u8 = 4;
+
+    let fd = stream.as_raw_fd();
+    let mut options = Vec::new();
+
+    match getsockopt::<libc::c_int>(fd, libc::IPPROTO_TCP, libc::TCP_MAXSEG) {
+        Ok(mss) => options.push(TcpOption::Mss(mss as u16)),
+        Err(e) => debug!("unable to read TCP_MAXSEG {}", e),
+    }
+
+    match getsockopt::<libc::tcp_info>(fd, libc::IPPROTO_TCP, libc::TCP_INFO) {
+        Ok(info) => {
+            if info.tcpi_options & TCPI_OPT_SACK != 0 {
+                options.push(TcpOption::SackPermitted);
+            }
+            if info.tcpi_options & TCPI_OPT_WSCALE != 0 {
+                options.push(TcpOption::WindowScale(snd_wscale(info.tcpi_snd_rcv_wscale)));
+            }
+        }
+        Err(e) => debug!("unable to read TCP_INFO {}", e),
+    }
+
+    options
+}
+
+/// Returns `tcpi_snd_wscale`, the window scale announced by the peer, from
+/// the `tcpi_snd_wscale : 4, tcpi_rcv_wscale : 4` bitfield of `tcp_info`.
+/// Bitfields are allocated from the low bits on little endian targets and
+/// from the high bits on big endian ones.
+#[cfg(target_os = "linux")]
+fn snd_wscale(snd_rcv_wscale: u8) -> u8 {
+    if cfg!(target_endian = "little") {
+        snd_rcv_wscale & 0x0f
+    } else {
+        snd_rcv_wscale >> 4
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn syn_options(_stream: &TcpStream) -> Vec<TcpOption> {
+    Vec::new()
+}
+
+/// Connect to `addr` applying the `options` which can be set on the socket.
+///
+/// MSS is set with `TCP_MAXSEG` and window scale is turned into a
+/// `TCP_WINDOW_CLAMP` so we never advertise a larger window than the client
+/// could. SACK and timestamps are system wide settings and are left as is.
+pub async fn connect(addr: SocketAddr, options: &[TcpOption]) -> io::Result<TcpStream> {
+    if options.is_empty() {
+        return TcpStream::connect(addr).await;
+    }
+
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+
+    for option in options {
+        match option {
+            TcpOption::Mss(mss) => {
+                if let Err(e) = socket.set_mss(u32::from(*mss)) {
+                    debug!("unable to set TCP_MAXSEG {} {}", mss, e);
+                }
+            }
+            TcpOption::WindowScale(shift) => {
+                if let Err(e) = set_window_clamp(&socket, *shift) {
+                    debug!("unable to set TCP_WINDOW_CLAMP {}", e);
+                }
+            }
+            o => debug!("ignoring tcp option {:?}", o),
+        }
+    }
+
+    TcpStream::connect_std(socket.into_tcp_stream(), &addr).await
+}
+
+/// Returns the destination a redirected connection was originally sent to.
+///
+/// iptables REDIRECT and DNAT keep it in `SO_ORIGINAL_DST`, while connections
+/// intercepted with TPROXY keep it as their local address. Connections made
+/// straight to the listener were neither, they are refused rather than
+/// tunneled back to the listener, even when conntrack gives them an
+/// `SO_ORIGINAL_DST`.
+#[cfg(target_os = "linux")]
+pub fn original_dst(stream: &TcpStream) -> io::Result<SocketAddr> {
+    use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+    use std::os::unix::io::AsRawFd;
+
+    // from linux/netfilter_ipv4.h and linux/netfilter_ipv6/ip6_tables.h
+    const SO_ORIGINAL_DST: libc::c_int = 80;
+    const IP6T_SO_ORIGINAL_DST: libc::c_int = 80;
+
+    let fd = stream.as_raw_fd();
+    let local = stream.local_addr()?;
+    let original = match local {
+        SocketAddr::V4(_) => getsockopt::<libc::sockaddr_in>(fd, libc::SOL_IP, SO_ORIGINAL_DST)
+            .map(|a| {
+                SocketAddr::V4(SocketAddrV4::new(
+                    Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr)),
+                    u16::from_be(a.sin_port),
+                ))
+            }),
+        SocketAddr::V6(_) => {
+            getsockopt::<libc::sockaddr_in6>(fd, libc::SOL_IPV6, IP6T_SO_ORIGINAL_DST).map(|a| {
+                SocketAddr::V6(SocketAddrV6::new(
+                    Ipv6Addr::from(a.sin6_addr.s6_addr),
+                    u16::from_be(a.sin6_port),
+                    a.sin6_flowinfo,
+                    a.sin6_scope_id,
+                ))
+            })
+        }
+    };
+
+    let e = match nat_original(original, local) {
+        Ok(addr) => return Ok(addr),
+        Err(e) => e,
+    };
+
+    // accepted sockets inherit IP_TRANSPARENT from the listener
+    let (level, name) = match local {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    };
+    let transparent = getsockopt::<libc::c_int>(fd, level, name).unwrap_or(0) != 0;
+    // a TPROXY connection's local address is a remote host's
+    let is_local = local.ip().is_loopback() || local_addrs()?.contains(&local.ip());
+    if transparent && !is_local {
+        return Ok(local);
+    }
+
+    debug!("unable to read SO_ORIGINAL_DST {}", e);
+    Err(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("connection to {} wasn't redirected", local),
+    ))
+}
+
+/// Returns the `SO_ORIGINAL_DST` of a connection made to `local`, as long as
+/// it was NATed. With conntrack loaded `SO_ORIGINAL_DST` also succeeds on
+/// connections that weren't, returning the local address itself.
+#[cfg(target_os = "linux")]
+fn nat_original(original: io::Result<SocketAddr>, local: SocketAddr) -> io::Result<SocketAddr> {
+    match original {
+        Ok(addr) if addr.ip() == local.ip() && addr.port() == local.port() => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "SO_ORIGINAL_DST is the local address",
+        )),
+        Ok(addr) => Ok(addr),
+        // ENOENT when the connection wasn't NATed
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn original_dst(_stream: &TcpStream) -> io::Result<SocketAddr> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "transparent proxy is only supported on linux",
+    ))
+}
+
+/// Returns the addresses of the host's network interfaces.
+#[cfg(target_os = "linux")]
+pub fn local_addrs() -> io::Result<Vec<IpAddr>> {
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    let mut ifaddrs: *mut libc::ifaddrs = std::ptr::null_mut();
+    if unsafe { libc::getifaddrs(&mut ifaddrs) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut addrs = Vec::new();
+    let mut cur = ifaddrs;
+    while !cur.is_null() {
+        let ifa = unsafe { &*cur };
+        cur = ifa.ifa_next;
+        if ifa.ifa_addr.is_null() {
+            continue;
+        }
+        match i32::from(unsafe { (*ifa.ifa_addr).sa_family }) {
+            libc::AF_INET => {
+                let a = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in) };
+                addrs.push(IpAddr::V4(Ipv4Addr::from(u32::from_be(a.sin_addr.s_addr))));
+            }
+            libc::AF_INET6 => {
+                let a = unsafe { &*(ifa.ifa_addr as *const libc::sockaddr_in6) };
+                addrs.push(IpAddr::V6(Ipv6Addr::from(a.sin6_addr.s6_addr)));
+            }
+            _ => {}
+        }
+    }
+    unsafe { libc::freeifaddrs(ifaddrs) };
+
+    Ok(addrs)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn local_addrs() -> io::Result<Vec<IpAddr>> {
+    Ok(Vec::new())
+}
+
+/// Returns the soft limit of open file descriptors of the process.
+#[cfg(target_os = "linux")]
+pub fn fd_limit() -> io::Result<u64> {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+    if unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(limit.rlim_cur)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn fd_limit() -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "file descriptor limit is only read on linux",
+    ))
+}
+
+/// Bind a listener on `addr`.
+/// The IPv6 unspecified address `[::]` accepts IPv4 connections as well.
+pub fn tcp_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let socket = new_socket(addr, Type::stream(), Protocol::tcp())?;
+    socket.set_reuse_address(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into_tcp_listener())
+}
+
+/// Bind a udp socket on `addr`.
+/// The IPv6 unspecified address `[::]` receives IPv4 datagrams as well.
+pub fn udp_socket(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = new_socket(addr, Type::dgram(), Protocol::udp())?;
+    socket.bind(&addr.into())?;
+    Ok(socket.into_udp_socket())
+}
+
+fn new_socket(addr: SocketAddr, ty: Type, protocol: Protocol) -> io::Result<Socket> {
+    match addr {
+        SocketAddr::V4(_) => Socket::new(Domain::ipv4(), ty, Some(protocol)),
+        SocketAddr::V6(v6) => {
+            let socket = Socket::new(Domain::ipv6(), ty, Some(protocol))?;
+            // don't depend on the system's default, e.g. net.ipv6.bindv6only
+            socket.set_only_v6(!v6.ip().is_unspecified())?;
+            Ok(socket)
+        }
+    }
+}
+
+/// Bind a listener accepting connections intercepted by TPROXY.
+///
+/// TPROXY needs `IP_TRANSPARENT` on the listening socket which requires
+/// `CAP_NET_ADMIN`, without it only REDIRECTed connections are accepted.
+pub fn transparent_listener(addr: SocketAddr) -> io::Result<TcpListener> {
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    socket.set_reuse_address(true)?;
+
+    if let Err(e) = set_transparent(&socket, addr) {
+        debug!("unable to set IP_TRANSPARENT, TPROXY is disabled {}", e);
+    }
+
+    socket.bind(&addr.into())?;
+    socket.listen(1024)?;
+    Ok(socket.into_tcp_listener())
+}
+
+#[cfg(target_os = "linux")]
+fn set_transparent(socket: &Socket, addr: SocketAddr) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let (level, name) = match addr {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_TRANSPARENT),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_TRANSPARENT),
+    };
+    setsockopt(socket.as_raw_fd(), level, name, 1 as libc::c_int)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_transparent(_socket: &Socket, _addr: SocketAddr) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "IP_TRANSPARENT is only supported on linux",
+    ))
+}
+
+#[cfg(target_os = "linux")]
+fn set_window_clamp(socket: &Socket, shift: u8) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    // RFC 7323 limits the shift count to 14
+    let clamp = (u16::MAX as libc::c_int) << shift.min(14);
+    setsockopt(
+        socket.as_raw_fd(),
+        libc::IPPROTO_TCP,
+        libc::TCP_WINDOW_CLAMP,
+        clamp,
+    )
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_window_clamp(_socket: &Socket, _shift: u8) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn getsockopt<T>(fd: libc::c_int, level: libc::c_int, name: libc::c_int) -> io::Result<T> {
+    let mut value = std::mem::MaybeUninit::<T>::zeroed();
+    let mut len = std::mem::size_of::<T>() as libc::socklen_t;
+    // Safety: `value` is a valid buffer of `len` bytes
+    let r = unsafe { libc::getsockopt(fd, level, name, value.as_mut_ptr() as *mut _, &mut len) };
+    if r == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: the value was zeroed and the kernel writes at most `len` bytes
+    Ok(unsafe { value.assume_init() })
+}
+
+#[cfg(target_os = "linux")]
+fn setsockopt<T>(
+    fd: libc::c_int,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: T,
+) -> io::Result<()> {
+    let len = std::mem::size_of::<T>() as libc::socklen_t;
+    // Safety: `value` lives for the duration of the call
+    let r = unsafe { libc::setsockopt(fd, level, name, &value as *const T as *const _, len) };
+    if r == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn connect_with_options_test() {
+        let mut listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let options = [TcpOption::Mss(1200), TcpOption::WindowScale(7)];
+        let (client, accepted) = tokio::join!(connect(addr, &options), listener.accept());
+        let client = client.unwrap();
+        let (server, _) = accepted.unwrap();
+
+        let syn = syn_options(&server);
+        if cfg!(target_os = "linux") {
+            assert!(syn
+                .iter()
+                .any(|o| matches!(o, TcpOption::Mss(mss) if *mss <= 1200)));
+            // timestamps values of the SYN aren't known
+            assert!(!syn.iter().any(|o| matches!(o, TcpOption::Timestamps(..))));
+        }
+        drop(client);
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn snd_wscale_test() {
+        let bits = if cfg!(target_endian = "little") {
+            0x27
+        } else {
+            0x72
+        };
+        assert_eq!(7, snd_wscale(bits));
+    }
+
+    #[tokio::test]
+    async fn dual_stack_test() {
+        let listener = match tcp_listener("[::]:0".parse().unwrap()) {
+            Ok(listener) => listener,
+            // ipv6 is disabled on the host
+            Err(_) => return,
+        };
+        let mut listener = TcpListener::from_std(listener).unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // ipv4 connections are accepted on [::]
+        let addr = SocketAddr::from(([127, 0, 0, 1], port));
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        client.unwrap();
+        accepted.unwrap();
+
+        let socket = udp_socket("[::]:0".parse().unwrap()).unwrap();
+        let port = socket.local_addr().unwrap().port();
+        let sender = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sender.send_to(b"ping", ("127.0.0.1", port)).unwrap();
+        let mut buf = [0; 4];
+        let (n, from) = socket.recv_from(&mut buf).unwrap();
+        assert_eq!(b"ping", &buf[..n]);
+        assert_eq!(sender.local_addr().unwrap().port(), from.port());
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn nat_original_test() {
+        let local: SocketAddr = "127.0.0.1:6970".parse().unwrap();
+        let original: SocketAddr = "10.0.0.1:443".parse().unwrap();
+        assert_eq!(original, nat_original(Ok(original), local).unwrap());
+
+        // conntrack answers with the local address for connections that weren't NATed
+        assert!(nat_original(Ok(local), local).is_err());
+        let local: SocketAddr = "[::1]:6970".parse().unwrap();
+        let original = SocketAddr::V6(std::net::SocketAddrV6::new(
+            "::1".parse().unwrap(),
+            6970,
+            0,
+            1,
+        ));
+        assert!(nat_original(Ok(original), local).is_err());
+
+        let enoent = io::Error::from_raw_os_error(libc::ENOENT);
+        assert!(nat_original(Err(enoent), local).is_err());
+    }
+
+    #[tokio::test]
+    async fn original_dst_test() {
+        let listener = transparent_listener("127.0.0.1:0".parse().unwrap()).unwrap();
+        let mut listener = TcpListener::from_std(listener).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        // without a redirection the connection was sent to the listener
+        // and is refused instead of looping back to it
+        let (client, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        let (server, _) = accepted.unwrap();
+        assert!(original_dst(&server).is_err());
+        drop(client);
+    }
+}
@@ -1,40 +1,536 @@
-use anyhow::{bail, ensure, Result};
-use std::convert::From;
+use crate::quic_tunnel::destination::is_valid_host;
+use anyhow::{ensure, Result};
+use bytes::{Buf, BufMut, BytesMut};
 use std::convert::TryInto;
-use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
 pub const TYPE_TCP_CONNECT: u8 = 0;
 pub const TYPE_TCP_CONNECT_OK: u8 = 1;
 pub const TYPE_ERROR: u8 = 2;
+pub const TYPE_TCP_EXTENDED_CONNECT: u8 = 3;
+pub const TYPE_TCP_CONNECT_HOSTNAME: u8 = 4;
+pub const TYPE_TCP_REVERSE_LISTEN: u8 = 5;
+pub const TYPE_UDP_ASSOCIATE: u8 = 6;
+pub const TYPE_UDP_FLOW: u8 = 7;
+pub const TYPE_AUTH: u8 = 8;
 pub const TYPE_END: u8 = 255;
 
-pub const ERROR_TYPE_PROTOCOL_VIOLATION: u8 = 0;
-pub const ERROR_TYPE_ICMP_PKT_RECV: u8 = 1;
-pub const ERROR_TYPE_MALFORMED_TLV_RECV: u8 = 2;
-pub const ERROR_TYPE_NETWORK_FAILURE: u8 = 3;
+pub const ERROR_TYPE_PROTOCOL_VIOLATION: u16 = 0;
+pub const ERROR_TYPE_ICMP_PKT_RECV: u16 = 1;
+pub const ERROR_TYPE_MALFORMED_TLV_RECV: u16 = 2;
+pub const ERROR_TYPE_NETWORK_FAILURE: u16 = 3;
+pub const ERROR_TYPE_ACCESS_DENIED: u16 = 4;
+pub const ERROR_TYPE_RESOURCE_LIMIT: u16 = 5;
+
+// Length of each TLV including the type and length bytes
+const TCP_CONNECT_LEN: u8 = 20;
+const TCP_CONNECT_OK_LEN: u8 = 2;
+// Minimum length, followed by the host name and TCP options
+const TCP_CONNECT_HOSTNAME_LEN: u8 = 5;
+// Minimum length, the error reason follows the error code
+const ERROR_LEN: u8 = 4;
+const TCP_REVERSE_LISTEN_LEN: u8 = 4;
+const UDP_ASSOCIATE_LEN: u8 = 2;
+const UDP_FLOW_LEN: u8 = 6;
+// Minimum length, followed by the token
+const AUTH_LEN: u8 = 2;
+const END_LEN: u8 = 2;
+
+// TCP option kinds carried by the TCP Extended Connect TLV
+pub const TCP_OPTION_MSS: u8 = 2;
+pub const TCP_OPTION_WINDOW_SCALE: u8 = 3;
+pub const TCP_OPTION_SACK_PERMITTED: u8 = 4;
+pub const TCP_OPTION_TIMESTAMPS: u8 = 8;
+
+/// QUIC tunnel stream TLV.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Tlv {
+    /// Remote TCP address the concentrator should connect to.
+    TcpConnect(SocketAddr),
+    /// Remote TCP address along with the TCP options of the client's SYN.
+    TcpExtendedConnect(SocketAddr, Vec<TcpOption>),
+    /// Domain name and port to be resolved by the concentrator,
+    /// along with the TCP options of the client's SYN.
+    TcpConnectHostname {
+        host: String,
+        port: u16,
+        options: Vec<TcpOption>,
+    },
+    TcpConnectOk,
+    /// Port the concentrator should listen on, sent before the TCP Connect TLV
+    /// of the client side destination its connections are forwarded to.
+    TcpReverseListen(u16),
+    /// Asks for a UDP flow to the address of the TCP Connect TLV following it.
+    UdpAssociate,
+    /// Identifier the concentrator assigned to a UDP flow,
+    /// prefixed to the datagrams of the flow.
+    UdpFlow(u32),
+    /// Token the client authenticates with, sent alone on the first stream.
+    Auth(Token),
+    /// Error code, one of `ERROR_TYPE_*`, with an optional reason.
+    Error(TlvError),
+    End,
+}
+
+/// Secret carried by the Auth TLV, kept out of the logs.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Token(pub Vec<u8>);
+
+impl fmt::Debug for Token {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Token(..)")
+    }
+}
+
+/// TCP option as carried in a SYN segment.
+/// Options are encoded back to back in TCP option format, without padding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TcpOption {
+    Mss(u16),
+    WindowScale(u8),
+    SackPermitted,
+    /// TSval and TSecr
+    Timestamps(u32, u32),
+    /// Any other option kind with its data.
+    Other(u8, Vec<u8>),
+}
+
+impl TcpOption {
+    /// Length of the option including kind and length bytes.
+    fn len(&self) -> usize {
+        match self {
+            TcpOption::Mss(_) => 4,
+            TcpOption::WindowScale(_) => 3,
+            TcpOption::SackPermitted => 2,
+            TcpOption::Timestamps(..) => 10,
+            TcpOption::Other(_, data) => 2 + data.len(),
+        }
+    }
+
+    fn encode(&self, buf: &mut BytesMut) {
+        match self {
+            TcpOption::Mss(mss) => {
+                buf.put_u8(TCP_OPTION_MSS);
+                buf.put_u8(4);
+                buf.put_u16(*mss);
+            }
+            TcpOption::WindowScale(shift) => {
+                buf.put_u8(TCP_OPTION_WINDOW_SCALE);
+                buf.put_u8(3);
+                buf.put_u8(*shift);
+            }
+            TcpOption::SackPermitted => {
+                buf.put_u8(TCP_OPTION_SACK_PERMITTED);
+                buf.put_u8(2);
+            }
+            TcpOption::Timestamps(tsval, tsecr) => {
+                buf.put_u8(TCP_OPTION_TIMESTAMPS);
+                buf.put_u8(10);
+                buf.put_u32(*tsval);
+                buf.put_u32(*tsecr);
+            }
+            TcpOption::Other(kind, data) => {
+                // the Tlv constructors reject longer options, see check_options
+                debug_assert!(data.len() <= 253, "tcp option too long");
+                buf.put_u8(*kind);
+                buf.put_u8(2 + data.len() as u8);
+                buf.put_slice(data);
+            }
+        }
+    }
+
+    /// Decode a list of options filling the whole of `buf`.
+    fn decode_all(mut buf: &[u8]) -> Result<Vec<TcpOption>> {
+        let mut options = Vec::new();
+        while !buf.is_empty() {
+            if buf.len() < 2 {
+                return Err(TlvError::malformed("short tcp option").into());
+            }
+            let kind = buf[0];
+            let len = buf[1] as usize;
+            if len < 2 || buf.len() < len {
+                return Err(
+                    TlvError::malformed(format!("invalid tcp option length {}", len)).into(),
+                );
+            }
+            let data = &buf[2..len];
+
+            let option = match (kind, len) {
+                (TCP_OPTION_MSS, 4) => TcpOption::Mss(u16::from_be_bytes(data.try_into()?)),
+                (TCP_OPTION_WINDOW_SCALE, 3) => TcpOption::WindowScale(data[0]),
+                (TCP_OPTION_SACK_PERMITTED, 2) => TcpOption::SackPermitted,
+                (TCP_OPTION_TIMESTAMPS, 10) => TcpOption::Timestamps(
+                    u32::from_be_bytes(data[0..4].try_into()?),
+                    u32::from_be_bytes(data[4..8].try_into()?),
+                ),
+                (TCP_OPTION_MSS, _)
+                | (TCP_OPTION_WINDOW_SCALE, _)
+                | (TCP_OPTION_SACK_PERMITTED, _)
+                | (TCP_OPTION_TIMESTAMPS, _) => {
+                    return Err(TlvError::malformed(format!(
+                        "invalid length {} for tcp option {}",
+                        len, kind
+                    ))
+                    .into())
+                }
+                // End of Option List and No-Operation are only used as padding
+                (0, _) | (1, _) => {
+                    return Err(TlvError::malformed("unexpected tcp option padding").into())
+                }
+                (kind, _) => TcpOption::Other(kind, data.to_vec()),
+            };
+            options.push(option);
+            buf = &buf[len..];
+        }
+        Ok(options)
+    }
+}
+
+/// Error carried by an Error TLV.
+///
+/// It is also raised when a received TLV can't be processed, `code` is then
+/// the error code to send back to the peer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TlvError {
+    /// One of `ERROR_TYPE_*`
+    pub code: u16,
+    /// UTF-8 reason, empty if the peer didn't send one
+    pub reason: String,
+}
+
+impl TlvError {
+    pub fn new(code: u16, reason: impl Into<String>) -> TlvError {
+        TlvError {
+            code,
+            reason: reason.into(),
+        }
+    }
 
-pub fn new_tcp_connect(buf: &mut [u8], addr: &SocketAddr) -> Result<usize> {
-    ensure!(buf.len() >= 20, "size of buffer needs to be at least 20");
+    pub fn protocol_violation(reason: impl Into<String>) -> TlvError {
+        TlvError::new(ERROR_TYPE_PROTOCOL_VIOLATION, reason)
+    }
+
+    pub fn malformed(reason: impl Into<String>) -> TlvError {
+        TlvError::new(ERROR_TYPE_MALFORMED_TLV_RECV, reason)
+    }
+
+    /// Map an error from connecting to the remote TCP server.
+    ///
+    /// Unreachable host or network are reported by ICMP, anything else
+    /// e.g. connection refused or timed out is a network failure.
+    pub fn from_io_error(e: &io::Error) -> TlvError {
+        let code = match e.kind() {
+            io::ErrorKind::HostUnreachable | io::ErrorKind::NetworkUnreachable => {
+                ERROR_TYPE_ICMP_PKT_RECV
+            }
+            _ => ERROR_TYPE_NETWORK_FAILURE,
+        };
+        TlvError::new(code, e.to_string())
+    }
+
+    /// Human readable name of the error code.
+    pub fn code_name(&self) -> &'static str {
+        match self.code {
+            ERROR_TYPE_PROTOCOL_VIOLATION => "protocol violation",
+            ERROR_TYPE_ICMP_PKT_RECV => "ICMP packet received",
+            ERROR_TYPE_MALFORMED_TLV_RECV => "malformed TLV",
+            ERROR_TYPE_NETWORK_FAILURE => "network failure",
+            ERROR_TYPE_ACCESS_DENIED => "access denied",
+            ERROR_TYPE_RESOURCE_LIMIT => "resource limit reached",
+            _ => "unknown error",
+        }
+    }
+}
+
+impl fmt::Display for TlvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.code_name(), self.code)?;
+        if !self.reason.is_empty() {
+            write!(f, ": {}", self.reason)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TlvError {}
 
-    // remote tcp IP address can't be multicast, broadcast or loopback address
-    // TODO: need to check for broadcast IP
+impl Tlv {
+    /// Create a TCP Connect TLV for `addr`.
+    pub fn tcp_connect(addr: SocketAddr) -> Result<Tlv> {
+        check_addr(&addr)?;
+
+        Ok(Tlv::TcpConnect(addr))
+    }
 
-    // commented this out for local testing
-    // ensure!(!addr.ip().is_loopback(), "loopback address is not allowed");
+    /// Create a TCP Extended Connect TLV for `addr` carrying `options`.
+    pub fn tcp_extended_connect(addr: SocketAddr, options: Vec<TcpOption>) -> Result<Tlv> {
+        Tlv::tcp_connect(addr)?;
+        check_options(TCP_CONNECT_LEN as usize, &options)?;
 
+        Ok(Tlv::TcpExtendedConnect(addr, options))
+    }
+
+    /// Create a TCP Connect Hostname TLV for `host` and `port` carrying `options`.
+    pub fn tcp_connect_hostname(host: &str, port: u16, options: Vec<TcpOption>) -> Result<Tlv> {
+        ensure!(is_valid_host(host), "invalid host {}", host);
+        check_options(TCP_CONNECT_HOSTNAME_LEN as usize + host.len(), &options)?;
+
+        Ok(Tlv::TcpConnectHostname {
+            host: host.to_string(),
+            port,
+            options,
+        })
+    }
+
+    /// Create an Auth TLV carrying `token`.
+    pub fn auth(token: &[u8]) -> Result<Tlv> {
+        ensure!(!token.is_empty(), "empty token");
+        ensure!(
+            AUTH_LEN as usize + token.len() <= u8::MAX as usize,
+            "token too long"
+        );
+
+        Ok(Tlv::Auth(Token(token.to_vec())))
+    }
+
+    /// Append the wire representation of the TLV to `buf`.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        match self {
+            Tlv::TcpConnect(addr) => {
+                buf.reserve(TCP_CONNECT_LEN as usize);
+                buf.put_u8(TYPE_TCP_CONNECT);
+                buf.put_u8(TCP_CONNECT_LEN);
+                encode_addr(buf, addr);
+            }
+            Tlv::TcpExtendedConnect(addr, options) => {
+                let options_len: usize = options.iter().map(TcpOption::len).sum();
+                let len = TCP_CONNECT_LEN as usize + options_len;
+                buf.reserve(len);
+                buf.put_u8(TYPE_TCP_EXTENDED_CONNECT);
+                buf.put_u8(len as u8);
+                encode_addr(buf, addr);
+                for option in options {
+                    option.encode(buf);
+                }
+            }
+            Tlv::TcpConnectHostname {
+                host,
+                port,
+                options,
+            } => {
+                let options_len: usize = options.iter().map(TcpOption::len).sum();
+                let len = TCP_CONNECT_HOSTNAME_LEN as usize + host.len() + options_len;
+                buf.reserve(len);
+                buf.put_u8(TYPE_TCP_CONNECT_HOSTNAME);
+                buf.put_u8(len as u8);
+                buf.put_u16(*port);
+                buf.put_u8(host.len() as u8);
+                buf.put_slice(host.as_bytes());
+                for option in options {
+                    option.encode(buf);
+                }
+            }
+            Tlv::TcpConnectOk => {
+                buf.put_u8(TYPE_TCP_CONNECT_OK);
+                buf.put_u8(TCP_CONNECT_OK_LEN);
+            }
+            Tlv::TcpReverseListen(port) => {
+                buf.reserve(TCP_REVERSE_LISTEN_LEN as usize);
+                buf.put_u8(TYPE_TCP_REVERSE_LISTEN);
+                buf.put_u8(TCP_REVERSE_LISTEN_LEN);
+                buf.put_u16(*port);
+            }
+            Tlv::UdpAssociate => {
+                buf.put_u8(TYPE_UDP_ASSOCIATE);
+                buf.put_u8(UDP_ASSOCIATE_LEN);
+            }
+            Tlv::UdpFlow(id) => {
+                buf.reserve(UDP_FLOW_LEN as usize);
+                buf.put_u8(TYPE_UDP_FLOW);
+                buf.put_u8(UDP_FLOW_LEN);
+                buf.put_u32(*id);
+            }
+            Tlv::Auth(token) => {
+                let len = AUTH_LEN as usize + token.0.len();
+                buf.reserve(len);
+                buf.put_u8(TYPE_AUTH);
+                buf.put_u8(len as u8);
+                buf.put_slice(&token.0);
+            }
+            Tlv::Error(err) => {
+                // the reason is truncated to fit in the TLV
+                let mut reason_len = err.reason.len().min(u8::MAX as usize - ERROR_LEN as usize);
+                while !err.reason.is_char_boundary(reason_len) {
+                    reason_len -= 1;
+                }
+
+                let len = ERROR_LEN as usize + reason_len;
+                buf.reserve(len);
+                buf.put_u8(TYPE_ERROR);
+                buf.put_u8(len as u8);
+                buf.put_u16(err.code);
+                buf.put_slice(&err.reason.as_bytes()[..reason_len]);
+            }
+            Tlv::End => {
+                buf.put_u8(TYPE_END);
+                buf.put_u8(END_LEN);
+            }
+        }
+    }
+
+    /// Decode the TLV at the start of `buf`.
+    /// Returns the TLV and the number of bytes it occupies.
+    ///
+    /// Short, unknown or badly sized TLVs are rejected with a `TlvError`
+    /// carrying `ERROR_TYPE_MALFORMED_TLV_RECV`.
+    pub fn decode(buf: &[u8]) -> Result<(Tlv, usize)> {
+        if buf.len() < 2 {
+            return Err(TlvError::malformed("short tlv").into());
+        }
+
+        let tlv_type = buf[0];
+        let len = buf[1] as usize;
+        if len < 2 || buf.len() < len {
+            return Err(TlvError::malformed(format!("invalid tlv length {}", len)).into());
+        }
+        let value = &buf[2..len];
+
+        let tlv = match tlv_type {
+            TYPE_TCP_CONNECT => {
+                check_len(tlv_type, len, TCP_CONNECT_LEN)?;
+                let addr = decode_addr(value)?;
+                check_addr(&addr).map_err(|e| TlvError::malformed(e.to_string()))?;
+                Tlv::TcpConnect(addr)
+            }
+            TYPE_TCP_EXTENDED_CONNECT => {
+                if len < TCP_CONNECT_LEN as usize {
+                    return Err(TlvError::malformed(format!(
+                        "invalid length {} for tlv type {}",
+                        len, tlv_type
+                    ))
+                    .into());
+                }
+                let addr = decode_addr(&value[..18])?;
+                check_addr(&addr).map_err(|e| TlvError::malformed(e.to_string()))?;
+                Tlv::TcpExtendedConnect(addr, TcpOption::decode_all(&value[18..])?)
+            }
+            TYPE_TCP_CONNECT_HOSTNAME => {
+                let host_len = if len >= TCP_CONNECT_HOSTNAME_LEN as usize {
+                    value[2] as usize
+                } else {
+                    0
+                };
+                if host_len == 0 || len < TCP_CONNECT_HOSTNAME_LEN as usize + host_len {
+                    return Err(TlvError::malformed(format!(
+                        "invalid length {} for tlv type {}",
+                        len, tlv_type
+                    ))
+                    .into());
+                }
+                let host = std::str::from_utf8(&value[3..3 + host_len])
+                    .ok()
+                    .filter(|h| is_valid_host(h))
+                    .ok_or_else(|| TlvError::malformed("invalid host name"))?;
+                Tlv::TcpConnectHostname {
+                    host: host.to_string(),
+                    port: u16::from_be_bytes(value[0..2].try_into()?),
+                    options: TcpOption::decode_all(&value[3 + host_len..])?,
+                }
+            }
+            TYPE_TCP_CONNECT_OK => {
+                check_len(tlv_type, len, TCP_CONNECT_OK_LEN)?;
+                Tlv::TcpConnectOk
+            }
+            TYPE_ERROR => {
+                if len < ERROR_LEN as usize {
+                    return Err(TlvError::malformed(format!(
+                        "invalid length {} for tlv type {}",
+                        len, tlv_type
+                    ))
+                    .into());
+                }
+                let code = u16::from_be_bytes(value[0..2].try_into()?);
+                let reason = std::str::from_utf8(&value[2..])
+                    .map_err(|_| TlvError::malformed("error reason is not UTF-8"))?;
+                Tlv::Error(TlvError::new(code, reason))
+            }
+            TYPE_TCP_REVERSE_LISTEN => {
+                check_len(tlv_type, len, TCP_REVERSE_LISTEN_LEN)?;
+                Tlv::TcpReverseListen(u16::from_be_bytes(value.try_into()?))
+            }
+            TYPE_UDP_ASSOCIATE => {
+                check_len(tlv_type, len, UDP_ASSOCIATE_LEN)?;
+                Tlv::UdpAssociate
+            }
+            TYPE_UDP_FLOW => {
+                check_len(tlv_type, len, UDP_FLOW_LEN)?;
+                Tlv::UdpFlow(u32::from_be_bytes(value.try_into()?))
+            }
+            TYPE_AUTH => {
+                if len == AUTH_LEN as usize {
+                    return Err(TlvError::malformed("empty token").into());
+                }
+                Tlv::Auth(Token(value.to_vec()))
+            }
+            TYPE_END => {
+                check_len(tlv_type, len, END_LEN)?;
+                Tlv::End
+            }
+            t => return Err(TlvError::malformed(format!("unknown tlv type {}", t)).into()),
+        };
+
+        Ok((tlv, len))
+    }
+}
+
+/// Check `addr` can be the destination of a TCP connection.
+fn check_addr(addr: &SocketAddr) -> Result<()> {
+    // loopback and other addresses of the concentrator's network are
+    // allowed or denied by the concentrator's ACL
     ensure!(
         !addr.ip().is_multicast(),
         "multicast address is not allowed"
     );
+    ensure!(
+        addr.ip() != IpAddr::V4(Ipv4Addr::BROADCAST),
+        "broadcast address is not allowed"
+    );
+    Ok(())
+}
 
-    // Type
-    buf[0] = TYPE_TCP_CONNECT;
+/// Check `options` fit in a TLV whose other fields take `len` bytes.
+fn check_options(len: usize, options: &[TcpOption]) -> Result<()> {
+    let options_len: usize = options.iter().map(TcpOption::len).sum();
+    ensure!(
+        len + options_len <= u8::MAX as usize,
+        "tcp options too long"
+    );
+    ensure!(
+        options.iter().all(|o| match o {
+            TcpOption::Other(kind, data) => *kind > 1 && data.len() <= 253,
+            _ => true,
+        }),
+        "invalid tcp option"
+    );
+    Ok(())
+}
 
-    // Length
-    buf[1] = 20;
+fn check_len(tlv_type: u8, len: usize, expected: u8) -> Result<()> {
+    if len != expected as usize {
+        return Err(TlvError::malformed(format!(
+            "invalid length {} for tlv type {}",
+            len, tlv_type
+        ))
+        .into());
+    }
+    Ok(())
+}
 
+fn encode_addr(buf: &mut BytesMut, addr: &SocketAddr) {
     // Remote Peer Port
-    buf[2..4].copy_from_slice(&addr.port().to_be_bytes());
+    buf.put_u16(addr.port());
 
     // Remote Peer IP Address
     // IPv4 addresses MUST be encoded using the IPv4-Mapped
@@ -43,75 +539,84 @@ pub fn new_tcp_connect(buf: &mut [u8], addr: &SocketAddr) -> Result<usize> {
         IpAddr::V4(ipv4) => ipv4.to_ipv6_mapped(),
         IpAddr::V6(ipv6) => ipv6,
     };
-
-    buf[4..20].copy_from_slice(&ipv6.octets());
-
-    Ok(20)
+    buf.put_slice(&ipv6.octets());
 }
 
-pub fn new_tcp_connect_ok(buf: &mut [u8]) -> Result<usize> {
-    // Type of TLV
-    buf[0] = TYPE_TCP_CONNECT_OK;
-
-    // Length
-    buf[1] = 2;
-
-    Ok(2)
-}
-
-pub fn new_end_tlv(buf: &mut [u8]) -> Result<usize> {
-    // Type of TLV
-    buf[0] = TYPE_END;
-
-    // Length
-    buf[1] = 2;
+fn decode_addr(value: &[u8]) -> Result<SocketAddr> {
+    let port = u16::from_be_bytes(value[0..2].try_into()?);
+    let ip_buf: [u8; 16] = value[2..18].try_into()?;
+    let ip = Ipv6Addr::from(ip_buf);
 
-    Ok(2)
+    // undo the IPv4-Mapped encoding
+    let ip = match ip.to_ipv4_mapped() {
+        Some(ipv4) => IpAddr::V4(ipv4),
+        None => IpAddr::V6(ip),
+    };
+    Ok(SocketAddr::new(ip, port))
 }
 
-pub fn new_error_tlv(buf: &mut [u8]) -> Result<usize> {
-    // Type of TLV
-    buf[0] = TYPE_ERROR;
-
-    let protocol_violation: u16 = 0x0;
-    // let ICMP_packet_received: u16 = 0x1;
-    // let malformed_tlv: u16 = 0x2;
-    // let network_failure: u16 = 0x3;
-
-    buf[2..4].copy_from_slice(&protocol_violation.to_be_bytes());
-
-    buf[1] = 4;
-
-    Ok(4)
+/// Decoder buffers bytes read from a quic stream and splits them into TLVs.
+///
+/// A TLV may be split across several reads, and application data may arrive
+/// in the same read as the last TLV. Bytes following the decoded TLVs are kept
+/// and returned by `into_payload` once the handshake is done.
+#[derive(Debug, Default)]
+pub struct Decoder {
+    buf: BytesMut,
 }
 
-pub fn is_tcp_connect_ok(buf: &[u8]) -> bool {
-    return buf[0] == TYPE_TCP_CONNECT_OK;
-}
+impl Decoder {
+    pub fn new() -> Decoder {
+        Decoder::default()
+    }
 
-pub fn parse_tcp_connect(buf: &[u8]) -> Result<SocketAddr> {
-    if buf[0] != TYPE_TCP_CONNECT {
-        bail!("Invalid TCP_CONNECT tlv");
+    /// Append bytes read from the stream.
+    pub fn extend(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
     }
-    let port = u16::from_be_bytes(buf[2..4].try_into()?);
-    let ip_buf: [u8; 16] = buf[4..20].try_into()?;
 
-    let ip = Ipv6Addr::from(ip_buf);
+    /// Returns the next complete TLV, or `None` if more data is needed.
+    pub fn next_tlv(&mut self) -> Result<Option<Tlv>> {
+        if self.buf.len() < 2 {
+            return Ok(None);
+        }
+
+        // Length covers the type and length bytes as well,
+        // wait for the rest of the TLV unless the length is invalid
+        let len = self.buf[1] as usize;
+        if len >= 2 && self.buf.len() < len {
+            return Ok(None);
+        }
+
+        let (tlv, n) = Tlv::decode(&self.buf)?;
+        self.buf.advance(n);
+        Ok(Some(tlv))
+    }
 
-    Ok(SocketAddr::new(IpAddr::V6(ip), port))
+    /// Consumes the decoder, returning the bytes received after the last TLV.
+    pub fn into_payload(self) -> BytesMut {
+        self.buf
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::net::Ipv4Addr;
+    use proptest::prelude::*;
+    use std::net::SocketAddrV6;
+
+    fn encode(tlv: &Tlv) -> BytesMut {
+        let mut buf = BytesMut::new();
+        tlv.encode(&mut buf);
+        buf
+    }
 
     #[test]
-    fn new_tcp_connect_v4_test() {
-        let mut buf: [u8; 20] = [0; 20];
-        let r = new_tcp_connect(&mut buf, &"10.0.0.1:8080".parse().unwrap());
+    fn tcp_connect_v4_encode_test() {
+        let tlv = Tlv::tcp_connect("10.0.0.1:8080".parse().unwrap()).unwrap();
+        let buf = encode(&tlv);
 
-        assert_eq!(20, r.unwrap());
+        assert_eq!(20, buf.len());
         assert_eq!(TYPE_TCP_CONNECT, buf[0]);
         assert_eq!(20, buf[1]);
         assert_eq!([31, 144], &buf[2..4]);
@@ -120,33 +625,347 @@ mod tests {
         assert_eq!([10, 0, 0, 1], &buf[16..20]);
     }
 
+    #[test]
+    fn tcp_connect_loopback_test() {
+        // the concentrator's ACL decides whether its loopback may be reached,
+        // so the TLV no longer refuses it
+        Tlv::tcp_connect("127.0.0.1:8080".parse().unwrap()).unwrap();
+    }
+
     #[test]
     #[should_panic]
-    fn new_tcp_connect_loopback_test() {
-        let mut buf: [u8; 20] = [0; 20];
-        new_tcp_connect(&mut buf, &"127.0.0.1:8080".parse().unwrap()).unwrap();
+    fn tcp_connect_multicast_test() {
+        Tlv::tcp_connect("224.0.0.1:8080".parse().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn tcp_connect_decode_multicast_test() {
+        for addr in &["224.0.0.1:8080", "255.255.255.255:8080", "[ff02::1]:8080"] {
+            let addr = addr.parse().unwrap();
+            for tlv in &[
+                Tlv::TcpConnect(addr),
+                Tlv::TcpExtendedConnect(addr, vec![TcpOption::SackPermitted]),
+            ] {
+                let err = Tlv::decode(&encode(tlv)).unwrap_err();
+                let err = err.downcast_ref::<TlvError>().unwrap();
+                assert_eq!(ERROR_TYPE_MALFORMED_TLV_RECV, err.code);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "tcp option too long")]
+    fn tcp_option_too_long_test() {
+        TcpOption::Other(42, vec![0; 254]).encode(&mut BytesMut::new());
     }
 
     #[test]
-    fn new_tcp_connect_ok_test() {
-        let mut buf: [u8; 12] = [0; 12];
-        let r = new_tcp_connect_ok(&mut buf);
+    fn tcp_connect_ok_encode_test() {
+        let buf = encode(&Tlv::TcpConnectOk);
 
-        assert_eq!(2, r.unwrap());
+        assert_eq!(2, buf.len());
         assert_eq!(TYPE_TCP_CONNECT_OK, buf[0]);
         assert_eq!(2, buf[1]);
     }
 
     #[test]
-    fn parse_tcp_connect_test() {
-        let mut buf: [u8; 20] = [0; 20];
-        let _ = new_tcp_connect(&mut buf, &"10.0.0.1:8080".parse().unwrap());
-        let r = parse_tcp_connect(&buf);
+    fn tcp_connect_decode_test() {
+        let buf = encode(&Tlv::TcpConnect("10.0.0.1:8080".parse().unwrap()));
+        let (tlv, n) = Tlv::decode(&buf).unwrap();
+
+        assert_eq!(20, n);
+        assert_eq!(
+            Tlv::TcpConnect(SocketAddr::new(
+                IpAddr::V4("10.0.0.1".parse::<Ipv4Addr>().unwrap()),
+                8080
+            )),
+            tlv
+        );
+    }
+
+    #[test]
+    fn decode_malformed_test() {
+        let malformed: &[&[u8]] = &[
+            &[],
+            &[TYPE_END],
+            &[TYPE_END, 1],
+            &[TYPE_END, 3],
+            &[TYPE_TCP_CONNECT, 20, 0, 80],
+            &[TYPE_ERROR, 2],
+            &[TYPE_ERROR, 5, 0, 0, 0xff],
+            &[TYPE_TCP_REVERSE_LISTEN, 3, 0],
+            &[TYPE_UDP_ASSOCIATE, 3, 0],
+            &[TYPE_UDP_FLOW, 4, 0, 0],
+            &[TYPE_AUTH, 2],
+            &[TYPE_AUTH, 4, 0],
+            &[9, 2],
+        ];
+        for buf in malformed {
+            let err = Tlv::decode(buf).unwrap_err();
+            let err = err.downcast_ref::<TlvError>().unwrap();
+            assert_eq!(ERROR_TYPE_MALFORMED_TLV_RECV, err.code);
+        }
+    }
+
+    #[test]
+    fn tcp_extended_connect_test() {
+        let options = vec![
+            TcpOption::Mss(1460),
+            TcpOption::SackPermitted,
+            TcpOption::Timestamps(0, 0),
+            TcpOption::WindowScale(7),
+        ];
+        let tlv = Tlv::tcp_extended_connect("10.0.0.1:8080".parse().unwrap(), options).unwrap();
+        let buf = encode(&tlv);
+
+        assert_eq!(TYPE_TCP_EXTENDED_CONNECT, buf[0]);
+        assert_eq!(39, buf[1]);
+        assert_eq!([TCP_OPTION_MSS, 4, 5, 180], &buf[20..24]);
+        assert_eq!((tlv, 39), Tlv::decode(&buf).unwrap());
+    }
+
+    #[test]
+    fn tcp_extended_connect_malformed_test() {
+        let addr = "10.0.0.1:8080".parse().unwrap();
+        let mut buf = encode(&Tlv::TcpExtendedConnect(addr, vec![]));
+        // No-Operation padding
+        buf.put_u8(1);
+        buf[1] += 1;
+        assert!(Tlv::decode(&buf).is_err());
+
+        let mut buf = encode(&Tlv::TcpExtendedConnect(addr, vec![]));
+        // MSS with a short length
+        buf.put_slice(&[TCP_OPTION_MSS, 3, 0]);
+        buf[1] += 3;
+        assert!(Tlv::decode(&buf).is_err());
+
+        let too_long = vec![TcpOption::Timestamps(0, 0); 24];
+        assert!(Tlv::tcp_extended_connect(addr, too_long).is_err());
+    }
+
+    #[test]
+    fn tcp_connect_hostname_test() {
+        let tlv =
+            Tlv::tcp_connect_hostname("db.internal", 5432, vec![TcpOption::Mss(1400)]).unwrap();
+        let buf = encode(&tlv);
+
+        assert_eq!(TYPE_TCP_CONNECT_HOSTNAME, buf[0]);
+        assert_eq!(20, buf[1]);
+        assert_eq!([21, 56, 11], &buf[2..5]);
+        assert_eq!(b"db.internal", &buf[5..16]);
+        assert_eq!((tlv, 20), Tlv::decode(&buf).unwrap());
+
+        assert!(Tlv::tcp_connect_hostname("", 80, vec![]).is_err());
+        assert!(Tlv::tcp_connect_hostname("a b", 80, vec![]).is_err());
+        // empty host name
+        assert!(Tlv::decode(&[TYPE_TCP_CONNECT_HOSTNAME, 5, 0, 80, 0]).is_err());
+        // host name longer than the TLV
+        assert!(Tlv::decode(&[TYPE_TCP_CONNECT_HOSTNAME, 6, 0, 80, 2, b'a']).is_err());
+    }
+
+    #[test]
+    fn tcp_reverse_listen_test() {
+        let buf = encode(&Tlv::TcpReverseListen(8080));
+
+        assert_eq!([TYPE_TCP_REVERSE_LISTEN, 4, 31, 144], &buf[..]);
+        assert_eq!((Tlv::TcpReverseListen(8080), 4), Tlv::decode(&buf).unwrap());
+    }
+
+    #[test]
+    fn udp_flow_test() {
+        let buf = encode(&Tlv::UdpFlow(0x0102_0304));
+
+        assert_eq!([TYPE_UDP_FLOW, 6, 1, 2, 3, 4], &buf[..]);
+        assert_eq!((Tlv::UdpFlow(0x0102_0304), 6), Tlv::decode(&buf).unwrap());
+        assert_eq!(
+            (Tlv::UdpAssociate, 2),
+            Tlv::decode(&[TYPE_UDP_ASSOCIATE, 2]).unwrap()
+        );
+    }
+
+    #[test]
+    fn auth_test() {
+        let tlv = Tlv::auth(b"s3cret").unwrap();
+        let buf = encode(&tlv);
 
-        assert_eq!(8080, r.as_ref().unwrap().port());
+        assert_eq!([TYPE_AUTH, 8], &buf[..2]);
+        assert_eq!(b"s3cret", &buf[2..]);
+        assert_eq!((tlv.clone(), 8), Tlv::decode(&buf).unwrap());
+        assert_eq!("Auth(Token(..))", format!("{:?}", tlv));
+
+        assert!(Tlv::auth(b"").is_err());
+        assert!(Tlv::auth(&[b'a'; 254]).is_err());
+    }
+
+    #[test]
+    fn error_encode_test() {
+        let err = TlvError::new(ERROR_TYPE_NETWORK_FAILURE, "refused");
+        let buf = encode(&Tlv::Error(err.clone()));
+
+        assert_eq!(TYPE_ERROR, buf[0]);
+        assert_eq!(11, buf[1]);
+        assert_eq!([0, 3], &buf[2..4]);
+        assert_eq!(b"refused", &buf[4..]);
+        assert_eq!((Tlv::Error(err), 11), Tlv::decode(&buf).unwrap());
+
+        // long reasons are cut on a char boundary
+        let err = TlvError::protocol_violation("é".repeat(200));
+        let buf = encode(&Tlv::Error(err));
+        assert_eq!(254, buf.len());
+        match Tlv::decode(&buf).unwrap().0 {
+            Tlv::Error(e) => assert_eq!("é".repeat(125), e.reason),
+            t => panic!("unexpected tlv {:?}", t),
+        }
+    }
+
+    #[test]
+    fn error_from_io_error_test() {
+        let refused = io::Error::from(io::ErrorKind::ConnectionRefused);
+        assert_eq!(
+            ERROR_TYPE_NETWORK_FAILURE,
+            TlvError::from_io_error(&refused).code
+        );
+        let unreachable = io::Error::from(io::ErrorKind::HostUnreachable);
         assert_eq!(
-            "10.0.0.1".parse::<Ipv4Addr>().unwrap().to_ipv6_mapped(),
-            r.as_ref().unwrap().ip()
+            ERROR_TYPE_ICMP_PKT_RECV,
+            TlvError::from_io_error(&unreachable).code
         );
     }
+
+    #[test]
+    fn decoder_split_tlv_test() {
+        let tlv = Tlv::TcpConnect("10.0.0.1:8080".parse().unwrap());
+        let buf = encode(&tlv);
+
+        let mut decoder = Decoder::new();
+        decoder.extend(&buf[..1]);
+        assert_eq!(None, decoder.next_tlv().unwrap());
+        decoder.extend(&buf[1..11]);
+        assert_eq!(None, decoder.next_tlv().unwrap());
+        decoder.extend(&buf[11..]);
+        assert_eq!(Some(tlv), decoder.next_tlv().unwrap());
+        assert_eq!(None, decoder.next_tlv().unwrap());
+    }
+
+    #[test]
+    fn decoder_payload_test() {
+        let tlv = Tlv::TcpConnect("10.0.0.1:8080".parse().unwrap());
+        let mut buf = encode(&tlv);
+        Tlv::End.encode(&mut buf);
+        buf.extend_from_slice(b"hello");
+
+        let mut decoder = Decoder::new();
+        decoder.extend(&buf);
+        assert_eq!(Some(tlv), decoder.next_tlv().unwrap());
+        assert_eq!(Some(Tlv::End), decoder.next_tlv().unwrap());
+        assert_eq!(&b"hello"[..], &decoder.into_payload()[..]);
+    }
+
+    #[test]
+    fn decoder_invalid_length_test() {
+        let mut decoder = Decoder::new();
+        decoder.extend(&[TYPE_TCP_CONNECT, 1, 0, 0]);
+        assert!(decoder.next_tlv().is_err());
+    }
+
+    fn socket_addr() -> impl Strategy<Value = SocketAddr> {
+        let addr = prop_oneof![
+            any::<(Ipv4Addr, u16)>().prop_map(|(ip, port)| SocketAddr::new(IpAddr::V4(ip), port)),
+            any::<(Ipv6Addr, u16)>()
+                .prop_filter("IPv4-mapped addresses decode as IPv4", |(ip, _)| {
+                    ip.to_ipv4_mapped().is_none()
+                })
+                .prop_map(|(ip, port)| SocketAddr::V6(SocketAddrV6::new(ip, port, 0, 0))),
+        ];
+        addr.prop_filter("not a tcp destination", |addr| check_addr(addr).is_ok())
+    }
+
+    fn tcp_option() -> impl Strategy<Value = TcpOption> {
+        prop_oneof![
+            any::<u16>().prop_map(TcpOption::Mss),
+            any::<u8>().prop_map(TcpOption::WindowScale),
+            Just(TcpOption::SackPermitted),
+            any::<(u32, u32)>().prop_map(|(tsval, tsecr)| TcpOption::Timestamps(tsval, tsecr)),
+            (
+                (5u8..=255).prop_filter("known option kind", |k| *k != TCP_OPTION_TIMESTAMPS),
+                prop::collection::vec(any::<u8>(), 0..8)
+            )
+                .prop_map(|(kind, data)| TcpOption::Other(kind, data)),
+        ]
+    }
+
+    fn tlv() -> impl Strategy<Value = Tlv> {
+        prop_oneof![
+            socket_addr().prop_map(Tlv::TcpConnect),
+            (socket_addr(), prop::collection::vec(tcp_option(), 0..8))
+                .prop_map(|(addr, options)| Tlv::TcpExtendedConnect(addr, options)),
+            (
+                "[a-z0-9-]{1,20}(\\.[a-z0-9_-]{1,20}){0,3}",
+                any::<u16>(),
+                prop::collection::vec(tcp_option(), 0..8)
+            )
+                .prop_map(|(host, port, options)| Tlv::TcpConnectHostname {
+                    host,
+                    port,
+                    options
+                }),
+            Just(Tlv::TcpConnectOk),
+            any::<u16>().prop_map(Tlv::TcpReverseListen),
+            Just(Tlv::UdpAssociate),
+            any::<u32>().prop_map(Tlv::UdpFlow),
+            prop::collection::vec(any::<u8>(), 1..32).prop_map(|t| Tlv::Auth(Token(t))),
+            (any::<u16>(), "\\PC{0,20}")
+                .prop_map(|(code, reason)| Tlv::Error(TlvError::new(code, reason))),
+            Just(Tlv::End),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip_prop(tlv in tlv(), trailing in prop::collection::vec(any::<u8>(), 0..8)) {
+            let mut buf = encode(&tlv);
+            let len = buf.len();
+            buf.extend_from_slice(&trailing);
+
+            let (decoded, n) = Tlv::decode(&buf).unwrap();
+            prop_assert_eq!(tlv, decoded);
+            prop_assert_eq!(len, n);
+        }
+
+        #[test]
+        fn decoder_round_trip_prop(
+            tlvs in prop::collection::vec(tlv(), 1..8),
+            payload in prop::collection::vec(any::<u8>(), 0..32),
+            chunk in 1usize..32,
+        ) {
+            let mut buf = BytesMut::new();
+            for tlv in &tlvs {
+                tlv.encode(&mut buf);
+            }
+            buf.extend_from_slice(&payload);
+
+            // feed the decoder in chunks and collect the expected number of TLVs
+            let mut decoder = Decoder::new();
+            let mut decoded = Vec::new();
+            for part in buf.chunks(chunk) {
+                decoder.extend(part);
+                while decoded.len() < tlvs.len() {
+                    match decoder.next_tlv().unwrap() {
+                        Some(tlv) => decoded.push(tlv),
+                        None => break,
+                    }
+                }
+            }
+            prop_assert_eq!(tlvs, decoded);
+            prop_assert_eq!(&payload[..], &decoder.into_payload()[..]);
+        }
+
+        #[test]
+        fn decode_arbitrary_bytes_prop(buf in prop::collection::vec(any::<u8>(), 0..64)) {
+            if let Ok((tlv, n)) = Tlv::decode(&buf) {
+                prop_assert_eq!(&buf[..n], &encode(&tlv)[..]);
+            }
+        }
+    }
 }
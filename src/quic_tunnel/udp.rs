@@ -0,0 +1,235 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::future;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::time::Duration;
+use tracing::{debug, warn};
+
+/// How long a UDP flow is kept without any datagram in either direction.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Datagrams of a flow buffered while its task is busy, the others are dropped.
+/// As many datagrams of flows not registered yet are kept.
+pub const FLOW_QUEUE: usize = 64;
+
+/// Size of the flow identifier prefixing the payload of each datagram.
+const FLOW_ID_SIZE: usize = 4;
+
+/// UDP flows of a quic connection.
+///
+/// Each flow is negotiated on its own stream, which stays open for the
+/// lifetime of the flow, and its datagrams are carried in quic DATAGRAM
+/// frames prefixed with the flow identifier.
+#[derive(Clone)]
+pub struct Flows {
+    connection: quinn::Connection,
+    /// Flows are closed once idle for this long
+    pub idle_timeout: Duration,
+    flows: Arc<Mutex<Routes>>,
+    next_id: Arc<AtomicU32>,
+}
+
+/// Where the datagrams of each flow go.
+#[derive(Default)]
+struct Routes {
+    flows: HashMap<u32, mpsc::Sender<Bytes>>,
+    /// Datagrams which arrived before their flow was registered, e.g. before
+    /// the client read the flow identifier, the oldest are dropped first.
+    early: VecDeque<(u32, Bytes)>,
+}
+
+impl Routes {
+    fn insert(&mut self, id: u32) -> mpsc::Receiver<Bytes> {
+        let (mut tx, rx) = mpsc::channel(FLOW_QUEUE);
+        let early = std::mem::take(&mut self.early);
+        for (flow, payload) in early {
+            if flow != id {
+                self.early.push_back((flow, payload));
+            } else if tx.try_send(payload).is_err() {
+                debug!("dropping datagram of busy udp flow {}", id);
+            }
+        }
+        self.flows.insert(id, tx);
+        rx
+    }
+
+    fn dispatch(&mut self, id: u32, payload: Bytes) {
+        match self.flows.get_mut(&id) {
+            Some(tx) => {
+                if tx.try_send(payload).is_err() {
+                    debug!("dropping datagram of busy udp flow {}", id);
+                }
+            }
+            None => {
+                if self.early.len() >= FLOW_QUEUE {
+                    let (id, _) = self.early.pop_front().unwrap();
+                    debug!("dropping datagram of unknown udp flow {}", id);
+                }
+                self.early.push_back((id, payload));
+            }
+        }
+    }
+}
+
+impl Flows {
+    pub fn new(connection: quinn::Connection, idle_timeout: Duration) -> Flows {
+        Flows {
+            connection,
+            idle_timeout,
+            flows: Arc::new(Mutex::new(Routes::default())),
+            next_id: Arc::new(AtomicU32::new(0)),
+        }
+    }
+
+    /// Allocate a flow, returns its identifier and the payloads received for it.
+    pub fn open(&self) -> (u32, mpsc::Receiver<Bytes>) {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        (id, self.insert(id))
+    }
+
+    /// Register the flow `id` allocated by the peer, returns the payloads
+    /// received for it, including those received before it was registered.
+    pub fn insert(&self, id: u32) -> mpsc::Receiver<Bytes> {
+        self.flows.lock().unwrap().insert(id)
+    }
+
+    pub fn remove(&self, id: u32) {
+        self.flows.lock().unwrap().flows.remove(&id);
+    }
+
+    /// Largest payload a datagram of a flow can carry, `None` if the peer
+    /// doesn't accept datagrams. It follows the path MTU, so it's usually
+    /// a little over a kilobyte.
+    pub fn max_payload_size(&self) -> Option<usize> {
+        self.connection
+            .max_datagram_size()
+            .map(|max| max.saturating_sub(FLOW_ID_SIZE))
+    }
+
+    /// Send `payload` on the flow `id`.
+    /// Like UDP, datagrams which can't be sent are dropped, and so are payloads
+    /// above `max_payload_size` as datagrams aren't fragmented.
+    pub fn send(&self, id: u32, payload: &[u8]) {
+        let datagram = match checked_encode(id, payload, self.connection.max_datagram_size()) {
+            Some(datagram) => datagram,
+            None => return,
+        };
+        if let Err(e) = self.connection.send_datagram(datagram) {
+            debug!("dropping datagram of udp flow {} {}", id, e);
+        }
+    }
+
+    /// Hand a datagram received on the connection over to its flow.
+    pub fn dispatch(&self, datagram: Bytes) {
+        let (id, payload) = match decode(datagram) {
+            Some(d) => d,
+            None => {
+                debug!("dropping datagram without flow identifier");
+                return;
+            }
+        };
+        self.flows.lock().unwrap().dispatch(id, payload);
+    }
+}
+
+/// Receive a datagram on a socket shared with the tasks sending on it.
+pub async fn recv_from(socket: &UdpSocket, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+    future::poll_fn(|cx| socket.poll_recv_from(cx, buf)).await
+}
+
+/// Send a datagram on a socket shared with the task receiving on it.
+pub async fn send_to(socket: &UdpSocket, buf: &[u8], target: &SocketAddr) -> io::Result<usize> {
+    future::poll_fn(|cx| socket.poll_send_to(cx, buf, target)).await
+}
+
+/// Encode `payload` of the flow `id` if it fits in `max_datagram_size`,
+/// `None` when it doesn't or the peer doesn't accept datagrams.
+fn checked_encode(id: u32, payload: &[u8], max_datagram_size: Option<usize>) -> Option<Bytes> {
+    let max = match max_datagram_size {
+        Some(max) => max,
+        None => {
+            warn!(
+                "dropping datagram of udp flow {}, the peer doesn't accept datagrams",
+                id
+            );
+            return None;
+        }
+    };
+    if FLOW_ID_SIZE + payload.len() > max {
+        warn!(
+            "dropping {} bytes datagram of udp flow {}, above the {} bytes a datagram carries",
+            payload.len(),
+            id,
+            max.saturating_sub(FLOW_ID_SIZE)
+        );
+        return None;
+    }
+    Some(encode(id, payload))
+}
+
+fn encode(id: u32, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(FLOW_ID_SIZE + payload.len());
+    buf.put_u32(id);
+    buf.put_slice(payload);
+    buf.freeze()
+}
+
+fn decode(mut datagram: Bytes) -> Option<(u32, Bytes)> {
+    if datagram.len() < FLOW_ID_SIZE {
+        return None;
+    }
+    let id = datagram.get_u32();
+    Some((id, datagram))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn datagram_test() {
+        let datagram = encode(7, b"query");
+        assert_eq!(&[0, 0, 0, 7, b'q'][..], &datagram[..5]);
+        assert_eq!(Some((7, Bytes::from_static(b"query"))), decode(datagram));
+
+        assert_eq!(Some((1, Bytes::new())), decode(encode(1, b"")));
+        assert_eq!(None, decode(Bytes::from_static(&[0, 0, 1])));
+    }
+
+    #[test]
+    fn early_datagram_test() {
+        let mut routes = Routes::default();
+        routes.dispatch(1, Bytes::from_static(b"early"));
+        routes.dispatch(2, Bytes::from_static(b"other"));
+
+        let mut rx = routes.insert(1);
+        assert_eq!(Bytes::from_static(b"early"), rx.try_recv().unwrap());
+        assert!(rx.try_recv().is_err());
+        assert_eq!(1, routes.early.len());
+
+        routes.dispatch(1, Bytes::from_static(b"late"));
+        assert_eq!(Bytes::from_static(b"late"), rx.try_recv().unwrap());
+
+        // only the latest datagrams of unknown flows are kept
+        for id in 3..3 + FLOW_QUEUE as u32 {
+            routes.dispatch(id, Bytes::new());
+        }
+        assert_eq!(FLOW_QUEUE, routes.early.len());
+        assert!(routes.early.iter().all(|(id, _)| *id != 2));
+    }
+
+    #[test]
+    fn oversized_test() {
+        let max = Some(FLOW_ID_SIZE + 1200);
+        let datagram = checked_encode(3, &[1; 1200], max).unwrap();
+        assert_eq!(FLOW_ID_SIZE + 1200, datagram.len());
+        assert_eq!(None, checked_encode(3, &[1; 1201], max));
+        assert_eq!(None, checked_encode(3, b"", Some(2)));
+        assert_eq!(None, checked_encode(3, b"query", None));
+    }
+}
@@ -0,0 +1,147 @@
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::str::FromStr;
+
+/// Version of the tunnel protocol, negotiated with ALPN.
+///
+/// * `tcpquic/1` - TCP Connect, TCP Connect OK, Error and End TLVs
+/// * `tcpquic/2` - adds the TCP Extended Connect TLV
+/// * `tcpquic/3` - adds the TCP Connect Hostname TLV
+/// * `tcpquic/4` - adds health checks, streams carrying the End TLV alone which
+///   the concentrator answers with TCP Connect OK without connecting anywhere.
+///   Older versions treat them as a protocol violation
+/// * `tcpquic/5` - adds the TCP Reverse Listen TLV
+/// * `tcpquic/6` - adds UDP flows, negotiated with the UDP Associate and UDP Flow TLVs
+/// * `tcpquic/7` - adds the Auth TLV, authenticating the client on its first stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Version {
+    V1,
+    V2,
+    V3,
+    V4,
+    V5,
+    V6,
+    V7,
+}
+
+impl Version {
+    /// Every supported version, most preferred first.
+    pub const ALL: &'static [Version] = &[
+        Version::V7,
+        Version::V6,
+        Version::V5,
+        Version::V4,
+        Version::V3,
+        Version::V2,
+        Version::V1,
+    ];
+
+    pub fn alpn(self) -> &'static [u8] {
+        match self {
+            Version::V1 => b"tcpquic/1",
+            Version::V2 => b"tcpquic/2",
+            Version::V3 => b"tcpquic/3",
+            Version::V4 => b"tcpquic/4",
+            Version::V5 => b"tcpquic/5",
+            Version::V6 => b"tcpquic/6",
+            Version::V7 => b"tcpquic/7",
+        }
+    }
+
+    pub fn from_alpn(protocol: &[u8]) -> Option<Version> {
+        Version::ALL.iter().copied().find(|v| v.alpn() == protocol)
+    }
+
+    pub fn supports_extended_connect(self) -> bool {
+        self >= Version::V2
+    }
+
+    pub fn supports_hostname(self) -> bool {
+        self >= Version::V3
+    }
+
+    pub fn supports_health_check(self) -> bool {
+        self >= Version::V4
+    }
+
+    pub fn supports_reverse(self) -> bool {
+        self >= Version::V5
+    }
+
+    pub fn supports_udp(self) -> bool {
+        self >= Version::V6
+    }
+
+    pub fn supports_auth(self) -> bool {
+        self >= Version::V7
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(std::str::from_utf8(self.alpn()).unwrap())
+    }
+}
+
+impl FromStr for Version {
+    type Err = anyhow::Error;
+
+    /// Parses either the version number or the full ALPN e.g. `2` or `tcpquic/2`.
+    fn from_str(s: &str) -> Result<Version> {
+        let s = s.trim();
+        Version::ALL
+            .iter()
+            .copied()
+            .find(|v| v.to_string() == s || v.to_string().trim_start_matches("tcpquic/") == s)
+            .ok_or_else(|| anyhow!("unsupported tunnel version {}", s))
+    }
+}
+
+/// Parse a comma separated list of versions, keeping the given order of preference.
+pub fn parse_versions(s: &str) -> Result<Vec<Version>> {
+    let mut versions = Vec::new();
+    for v in s.split(',') {
+        let v: Version = v.parse()?;
+        if !versions.contains(&v) {
+            versions.push(v);
+        }
+    }
+    Ok(versions)
+}
+
+/// Version negotiated with ALPN on `connection`.
+/// `None` if the peer didn't agree on any tunnel version.
+pub fn negotiated(connection: &quinn::Connection) -> Option<Version> {
+    connection
+        .authentication_data()
+        .protocol
+        .as_deref()
+        .and_then(Version::from_alpn)
+}
+
+/// ALPN protocols to advertise for `versions`.
+pub fn alpn_protocols(versions: &[Version]) -> Vec<&'static [u8]> {
+    versions.iter().map(|v| v.alpn()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_versions_test() {
+        assert_eq!(
+            vec![Version::V1, Version::V2],
+            parse_versions("1, tcpquic/2,1").unwrap()
+        );
+        assert!(parse_versions("8").is_err());
+    }
+
+    #[test]
+    fn alpn_test() {
+        for v in Version::ALL {
+            assert_eq!(Some(*v), Version::from_alpn(v.alpn()));
+        }
+        assert_eq!(None, Version::from_alpn(b"hq-29"));
+    }
+}
@@ -0,0 +1,460 @@
+use crate::auth::{self, Credentials};
+use crate::quic_tunnel::connection;
+use crate::quic_tunnel::ratelimit::ClientLimits;
+use crate::quic_tunnel::reverse::Registration;
+use crate::quic_tunnel::tlv::{self, Tlv};
+use crate::quic_tunnel::udp::{self, Flows};
+use crate::quic_tunnel::version::{self, Version};
+use crate::Shutdown;
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use quinn::VarInt;
+use rand::Rng;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use tokio::sync::{broadcast, mpsc, watch, Notify};
+use tokio::time::{self, Duration};
+use tracing::{debug, error, info};
+
+/// Delay before the first reconnection attempt, doubled after each failure.
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Health checks are round trips of the End TLV alone on a stream of the tunnel,
+/// from `tcpquic/4`. The tunnel is closed after `MAX_HEALTH_CHECK_FAILURES`
+/// consecutive health checks without an answer, failing with a transport error
+/// or getting no stream or no reply within `HEALTH_CHECK_TIMEOUT`.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_HEALTH_CHECK_FAILURES: u32 = 3;
+
+/// Connections opened on demand are closed after carrying no stream for this long.
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Quic connection to the concentrator and the tunnel protocol version negotiated on it.
+#[derive(Clone)]
+pub struct Tunnel {
+    pub connection: quinn::Connection,
+    pub version: Version,
+    /// Duration of the last successful health check,
+    /// or of the handshake until the first one
+    pub latency: Duration,
+    /// UDP flows opened on the connection
+    pub flows: Flows,
+}
+
+/// Current tunnel published by the `Supervisor`, `None` while reconnecting.
+pub type TunnelWatch = watch::Receiver<Option<Tunnel>>;
+
+/// Demand for an extra connection of a pool, which is only kept open while used.
+#[derive(Clone)]
+pub struct Demand {
+    notify: Arc<Notify>,
+    started: Arc<AtomicBool>,
+    /// Active streams of the connection
+    streams: Arc<AtomicUsize>,
+}
+
+impl Demand {
+    pub fn new(streams: Arc<AtomicUsize>) -> Demand {
+        Demand {
+            notify: Arc::new(Notify::new()),
+            started: Arc::new(AtomicBool::new(false)),
+            streams,
+        }
+    }
+
+    /// Start the connection, `false` if it's already started.
+    pub fn start(&self) -> bool {
+        if self.started.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+        self.notify.notify();
+        true
+    }
+
+    /// `true` from `start` until the connection is closed for being idle.
+    pub fn is_started(&self) -> bool {
+        self.started.load(Ordering::Relaxed)
+    }
+
+    fn is_idle(&self) -> bool {
+        self.streams.load(Ordering::Relaxed) == 0
+    }
+
+    fn stop(&self) {
+        self.started.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Keeps the client's quic connection to the concentrator open,
+/// reconnecting with exponential backoff and jitter whenever it's closed.
+pub struct Supervisor {
+    endpoint: quinn::Endpoint,
+    server_addr: SocketAddr,
+    server_name: String,
+    tunnel_tx: watch::Sender<Option<Tunnel>>,
+    /// Delays the connection until started, and closes it once idle
+    demand: Option<Demand>,
+    /// Reverse tunnels registered on each new connection
+    reverse: Option<Registration>,
+    /// Token the client authenticates with on each new connection
+    credentials: Option<Credentials>,
+}
+
+impl Supervisor {
+    pub fn new(
+        endpoint: quinn::Endpoint,
+        server_addr: SocketAddr,
+        server_name: &str,
+        demand: Option<Demand>,
+        reverse: Option<Registration>,
+        credentials: Option<Credentials>,
+    ) -> (Supervisor, TunnelWatch) {
+        let (tunnel_tx, tunnel_rx) = watch::channel(None);
+        let supervisor = Supervisor {
+            endpoint,
+            server_addr,
+            server_name: server_name.to_string(),
+            tunnel_tx,
+            demand,
+            reverse,
+            credentials,
+        };
+        (supervisor, tunnel_rx)
+    }
+
+    /// Connect to the concentrator and reconnect each time the connection is closed.
+    /// Only returns on errors which retrying can't fix, like an invalid server name
+    /// or a token the concentrator refuses.
+    pub async fn run(&mut self) -> Result<()> {
+        let mut attempt = 0;
+        let mut idle = self.demand.is_some();
+        loop {
+            if let Some(demand) = self.demand.as_ref().filter(|_| idle) {
+                demand.notify.notified().await;
+                idle = false;
+            }
+
+            let (new_conn, latency) = match self.connect().await? {
+                Some(connected) => connected,
+                None => {
+                    let delay = backoff(attempt);
+                    attempt += 1;
+                    debug!("reconnecting to quic server in {:?}", delay);
+                    time::delay_for(delay).await;
+                    continue;
+                }
+            };
+            attempt = 0;
+
+            let quinn::NewConnection {
+                connection,
+                mut bi_streams,
+                mut datagrams,
+                ..
+            } = new_conn;
+
+            let version = match version::negotiated(&connection) {
+                Some(v) => v,
+                None => {
+                    error!("concentrator doesn't support offered tunnel versions");
+                    connection.close(VarInt::from_u32(0), b"unsupported tunnel version");
+                    time::delay_for(MAX_BACKOFF).await;
+                    continue;
+                }
+            };
+            if let Some(credentials) = &self.credentials {
+                if let Err(e) = credentials.authenticate(&connection, version).await {
+                    connection.close(VarInt::from_u32(0), b"authentication failed");
+                    if !version.supports_auth() || auth::is_rejected(&e) {
+                        return Err(anyhow!(
+                            "{} refused to authenticate the client {}",
+                            self.server_addr,
+                            e
+                        ));
+                    }
+                    error!("unable to authenticate to {} {}", self.server_addr, e);
+                    time::delay_for(MAX_BACKOFF).await;
+                    continue;
+                }
+            }
+            info!(
+                "connected to quic server at {} with tunnel version {} in {:?}",
+                self.server_addr, version, latency
+            );
+            let mut tunnel = Tunnel {
+                flows: Flows::new(connection.clone(), udp::DEFAULT_IDLE_TIMEOUT),
+                connection,
+                version,
+                latency,
+            };
+            let _ = self.tunnel_tx.broadcast(Some(tunnel.clone()));
+
+            let reverse_dests = self.reverse.as_ref().map(Registration::dests);
+
+            // channel to notify the streams of reverse tunnels
+            let (notify_shutdown, _) = broadcast::channel(1);
+            let (shutdown_complete_tx, mut shutdown_complete_rx) = mpsc::channel(1);
+
+            // the control streams are kept open for as long as the connection
+            match &self.reverse {
+                Some(reverse) if version.supports_reverse() => {
+                    let reverse = reverse.clone();
+                    let connection = tunnel.connection.clone();
+                    let shutdown = Shutdown::new(notify_shutdown.subscribe());
+                    let shutdown_complete = shutdown_complete_tx.clone();
+                    tokio::spawn(async move {
+                        reverse.run(connection, version, shutdown).await;
+                        drop(shutdown_complete);
+                    });
+                }
+                Some(_) => error!("{} doesn't support reverse tunnels", version),
+                None => {}
+            }
+
+            let mut health_checks = time::interval_at(
+                time::Instant::now() + HEALTH_CHECK_INTERVAL,
+                HEALTH_CHECK_INTERVAL,
+            );
+            let mut failures = 0;
+            // health checks run in their own task so they don't hold the loop
+            let (probe_tx, mut probe_rx) = mpsc::channel(1);
+            let mut probing = false;
+            let mut idle_checks = time::interval_at(
+                time::Instant::now() + IDLE_CHECK_INTERVAL,
+                IDLE_CHECK_INTERVAL,
+            );
+            let mut idle_since = None;
+            // the concentrator only opens streams for reverse tunnels,
+            // the stream of incoming streams ends with the close reason
+            loop {
+                tokio::select! {
+                    incoming = bi_streams.next() => match incoming {
+                        Some(Ok((quic_send, quic_recv))) => match (&self.reverse, &reverse_dests) {
+                            (Some(reverse), Some(dests)) => {
+                                let mut conn = connection::Connection {
+                                    version,
+                                    handshake_timeout: reverse.handshake_timeout,
+                                    reverse: None,
+                                    reverse_dests: Some(dests.clone()),
+                                    udp: None,
+                                    acl: None,
+                                    limits: ClientLimits::default(),
+                                    admission: None,
+                                    shutdown: Shutdown::new(notify_shutdown.subscribe()),
+                                    _shutdown_complete: shutdown_complete_tx.clone(),
+                                };
+                                // Spawn a new task to process each stream.
+                                tokio::spawn(async move {
+                                    if let Err(err) = conn.run_concentrator_conn(quic_send, quic_recv).await {
+                                        error!(cause = ? err, "reverse stream error");
+                                    }
+                                });
+                            }
+                            _ => debug!("ignoring stream opened by the concentrator"),
+                        },
+                        Some(Err(reason)) => {
+                            error!("quic connection to {} closed {}", self.server_addr, reason);
+                            break;
+                        }
+                        None => {
+                            info!("quic connection to {} closed locally", self.server_addr);
+                            break;
+                        }
+                    },
+                    Some(Ok(datagram)) = datagrams.next() => tunnel.flows.dispatch(datagram),
+                    // older concentrators only have the quic keep-alives
+                    _ = health_checks.tick(), if !probing && version.supports_health_check() => {
+                        probing = true;
+                        let mut probe_tx = probe_tx.clone();
+                        let connection = tunnel.connection.clone();
+                        let server_addr = self.server_addr;
+                        tokio::spawn(async move {
+                            let _ = probe_tx.send(health_check(connection, server_addr).await).await;
+                        });
+                    }
+                    Some(probe) = probe_rx.recv() => {
+                        probing = false;
+                        match probe {
+                            Probe::Healthy(latency) => {
+                                failures = 0;
+                                tunnel.latency = latency;
+                                let _ = self.tunnel_tx.broadcast(Some(tunnel.clone()));
+                            }
+                            Probe::Busy => failures = 0,
+                            Probe::Failed => {
+                                failures += 1;
+                                if failures >= MAX_HEALTH_CHECK_FAILURES {
+                                    error!("quic server at {} failed {} health checks", self.server_addr, failures);
+                                    tunnel.connection.close(VarInt::from_u32(0), b"health check failed");
+                                    break;
+                                }
+                            }
+                        }
+                    },
+                    _ = idle_checks.tick(), if self.demand.is_some() => {
+                        let demand = self.demand.as_ref().unwrap();
+                        if !demand.is_idle() {
+                            idle_since = None;
+                            continue;
+                        }
+                        let since = *idle_since.get_or_insert_with(time::Instant::now);
+                        if since.elapsed() >= IDLE_TIMEOUT {
+                            debug!("closing idle quic connection to {}", self.server_addr);
+                            let _ = self.tunnel_tx.broadcast(None);
+                            demand.stop();
+                            tunnel.connection.close(VarInt::from_u32(0), b"idle");
+                            idle = true;
+                            break;
+                        }
+                    }
+                }
+            }
+            let _ = self.tunnel_tx.broadcast(None);
+
+            drop(notify_shutdown);
+            drop(shutdown_complete_tx);
+            let _ = shutdown_complete_rx.recv().await;
+        }
+    }
+
+    /// Returns the connection and the duration of its handshake,
+    /// or `None` if the connection failed in a way a retry could fix.
+    async fn connect(&self) -> Result<Option<(quinn::NewConnection, Duration)>> {
+        let start = time::Instant::now();
+        let connecting = self
+            .endpoint
+            .connect(&self.server_addr, &self.server_name)?;
+        match connecting.await {
+            Ok(new_conn) => Ok(Some((new_conn, start.elapsed()))),
+            Err(e) => {
+                error!(
+                    "failed to connect to quic server at {} {}",
+                    self.server_addr, e
+                );
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Outcome of a health check.
+enum Probe {
+    /// The concentrator answered the round trip in this long
+    Healthy(Duration),
+    /// The concentrator answered with something other than TCP Connect OK,
+    /// e.g. an Error TLV, or reset the stream
+    Busy,
+    /// No answer, the stream failed with a transport error or timed out
+    Failed,
+}
+
+/// Round trip the End TLV alone on a new stream of the tunnel, which the
+/// concentrator answers without connecting anywhere.
+async fn health_check(connection: quinn::Connection, server_addr: SocketAddr) -> Probe {
+    let start = time::Instant::now();
+    let reply = time::timeout(HEALTH_CHECK_TIMEOUT, async {
+        let (mut quic_send, mut quic_recv) = connection.open_bi().await?;
+        connection::write_tlv(&mut quic_send, &Tlv::End).await?;
+        quic_send.finish().await?;
+        let mut decoder = tlv::Decoder::new();
+        connection::read_tlv(&mut quic_recv, &mut decoder).await
+    })
+    .await;
+    match reply {
+        Ok(Ok(Some(Tlv::TcpConnectOk))) => Probe::Healthy(start.elapsed()),
+        Ok(Ok(Some(tlv))) => {
+            debug!("health check of {} answered {:?}", server_addr, tlv);
+            Probe::Busy
+        }
+        Ok(Ok(None)) => {
+            debug!("health check stream of {} finished", server_addr);
+            Probe::Busy
+        }
+        Ok(Err(e)) if is_answer(&e) => {
+            debug!("health check of {} answered {}", server_addr, e);
+            Probe::Busy
+        }
+        Ok(Err(e)) => {
+            debug!("health check of {} failed {}", server_addr, e);
+            Probe::Failed
+        }
+        Err(_) => {
+            debug!("health check of {} timed out", server_addr);
+            Probe::Failed
+        }
+    }
+}
+
+/// `true` if the health check stream failed because of the concentrator
+/// rather than the transport, e.g. it reset the stream or sent something
+/// other than a TLV.
+fn is_answer(e: &anyhow::Error) -> bool {
+    e.is::<tlv::TlvError>()
+        || matches!(e.downcast_ref(), Some(quinn::ReadError::Reset(_)))
+        || matches!(e.downcast_ref(), Some(quinn::WriteError::Stopped(_)))
+}
+
+/// Exponential backoff with jitter, so clients don't reconnect all at once
+/// after a concentrator restart.
+fn backoff(attempt: u32) -> Duration {
+    let max = MIN_BACKOFF
+        .checked_mul(1 << attempt.min(16))
+        .map_or(MAX_BACKOFF, |d| d.min(MAX_BACKOFF));
+    rand::thread_rng().gen_range(max / 2, max)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_answer_test() {
+        let e = tlv::TlvError::new(tlv::ERROR_TYPE_NETWORK_FAILURE, "refused");
+        assert!(is_answer(&e.into()));
+        assert!(is_answer(
+            &quinn::ReadError::Reset(VarInt::from_u32(0)).into()
+        ));
+        assert!(is_answer(
+            &quinn::WriteError::Stopped(VarInt::from_u32(0)).into()
+        ));
+
+        let lost = quinn::ConnectionError::TimedOut;
+        assert!(!is_answer(
+            &quinn::ReadError::ConnectionClosed(lost.clone()).into()
+        ));
+        assert!(!is_answer(
+            &quinn::WriteError::ConnectionClosed(lost).into()
+        ));
+    }
+
+    #[test]
+    fn demand_test() {
+        let streams = Arc::new(AtomicUsize::new(0));
+        let demand = Demand::new(streams.clone());
+        assert!(!demand.is_started());
+        assert!(demand.start());
+        assert!(!demand.start());
+        assert!(demand.is_started());
+
+        assert!(demand.is_idle());
+        streams.fetch_add(1, Ordering::Relaxed);
+        assert!(!demand.is_idle());
+
+        demand.stop();
+        assert!(demand.start());
+    }
+
+    #[test]
+    fn backoff_test() {
+        for attempt in 0..64 {
+            let max = (MIN_BACKOFF * 2u32.pow(attempt.min(16))).min(MAX_BACKOFF);
+            let delay = backoff(attempt);
+            assert!(delay >= max / 2 && delay <= max, "{:?}", delay);
+        }
+        assert!(backoff(64) >= MAX_BACKOFF / 2);
+    }
+}